@@ -0,0 +1,156 @@
+use crate::expansion::{Chunk, ChunkId, Chunks, Role};
+use crate::lexer::Token;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::mem::take;
+
+impl<'src> Chunks<'src> {
+    /// Merge the chunks that can't be told apart: the ones with the same tokens and role, followed
+    /// by the same chunks, and either all accepting or all not. Merging happens bottom-up, so
+    /// whole identical tails of the graph are shared, and the resulting graph matches the same
+    /// token sequences as before.
+    pub(crate) fn dedup_suffixes(&mut self) {
+        type Key<'src> = (Vec<Token<'src>>, Role, Vec<ChunkId>, bool);
+
+        let mut merged_into = Vec::with_capacity(self.inner.len());
+        let mut seen: BTreeMap<Key<'src>, ChunkId> = BTreeMap::new();
+        let mut inner = Vec::new();
+        // Children are always allocated before their parents, so by the time a chunk is visited
+        // all of its children were already merged.
+        for (id, chunk) in take(&mut self.inner).into_iter().enumerate() {
+            let childs = remap(&merged_into, &chunk.childs);
+            let key = (
+                chunk.tokens,
+                chunk.role,
+                childs,
+                self.accepting.contains(&ChunkId(id)),
+            );
+            let new_id = *seen
+                .entry(key)
+                .or_insert_with_key(|(tokens, role, childs, _)| {
+                    inner.push(Chunk {
+                        tokens: tokens.clone(),
+                        childs: childs.clone(),
+                        role: *role,
+                    });
+                    ChunkId(inner.len() - 1)
+                });
+            merged_into.push(new_id);
+        }
+
+        self.inner = inner;
+        self.firsts = remap(&merged_into, &self.firsts);
+        self.accepting = self.accepting.iter().map(|id| merged_into[id.0]).collect();
+        self.prune_unreachable();
+    }
+
+    /// Remove the chunks that can't be reached from the first chunks, changing the IDs of the
+    /// remaining ones.
+    pub(crate) fn prune_unreachable(&mut self) {
+        let mut reachable = BTreeSet::new();
+        for first in &self.firsts {
+            reachable.extend(self.reachable(*first));
+        }
+
+        // Keeping the order of the chunks ensures children still come before their parents.
+        let mut new_ids = BTreeMap::new();
+        let mut inner = Vec::with_capacity(reachable.len());
+        for (id, mut chunk) in take(&mut self.inner).into_iter().enumerate() {
+            if !reachable.contains(&ChunkId(id)) {
+                continue;
+            }
+            // Children of reachable chunks are reachable, so they were already renumbered.
+            chunk.childs = chunk.childs.iter().map(|child| new_ids[child]).collect();
+            new_ids.insert(ChunkId(id), ChunkId(inner.len()));
+            inner.push(chunk);
+        }
+
+        self.inner = inner;
+        self.firsts = self.firsts.iter().map(|id| new_ids[id]).collect();
+        self.accepting = self
+            .accepting
+            .iter()
+            .filter_map(|id| new_ids.get(id).copied())
+            .collect();
+    }
+}
+
+/// Replace the IDs with the ones they were merged into, removing the duplicates this creates.
+fn remap(merged_into: &[ChunkId], ids: &[ChunkId]) -> Vec<ChunkId> {
+    let mut result = Vec::with_capacity(ids.len());
+    for id in ids {
+        let new_id = merged_into[id.0];
+        if !result.contains(&new_id) {
+            result.push(new_id);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expansion::{of, Chunk, ChunkId, Chunks, Role};
+    use crate::lexer::Token;
+
+    #[test]
+    fn test_dedup_suffixes() {
+        // Expansion of `[$(1),*]` where each path has its own copy of the tail.
+        let mut chunks = Chunks::new(usize::MAX);
+        let mut add = |tokens: &[Token<'static>], childs: &[usize], role| {
+            let childs = childs.iter().copied().map(ChunkId).collect();
+            let tokens = tokens.to_vec();
+            let id = chunks
+                .allocate(Chunk {
+                    tokens,
+                    childs,
+                    role,
+                })
+                .unwrap();
+            id.0
+        };
+        let close = add(&[Token::CloseSquare], &[], Role::Delimiter);
+        let one = add(&[Token::Number(1)], &[close], Role::Content);
+        let close_two = add(&[Token::CloseSquare], &[], Role::Delimiter);
+        let last = add(&[Token::Number(1)], &[close_two], Role::Content);
+        let comma = add(&[Token::Comma], &[last], Role::Separator);
+        let first = add(&[Token::Number(1)], &[comma], Role::Content);
+        let close_empty = add(&[Token::CloseSquare], &[], Role::Delimiter);
+        let open = add(
+            &[Token::OpenSquare],
+            &[close_empty, one, first],
+            Role::Delimiter,
+        );
+        chunks.firsts = vec![ChunkId(open)];
+        chunks.accepting = [close, close_two, close_empty].map(ChunkId).into();
+
+        chunks.dedup_suffixes();
+        assert_eq!(5, chunks.inner.len());
+        assert!(chunks == of("[$(1),*]").unwrap());
+    }
+
+    #[test]
+    fn test_prune_unreachable() {
+        let mut chunks = of("[$(1),*]").unwrap();
+        // Orphan the chunks of the repetition with two elements.
+        let open = chunks.firsts[0];
+        chunks.inner[open.0]
+            .childs
+            .retain(|child| *child != ChunkId(3));
+
+        chunks.prune_unreachable();
+        assert_eq!(3, chunks.inner.len());
+        assert_eq!(
+            "#2 [\n  #0 ]\n  #1 1\n    #0 (see above)\n",
+            chunks.display_tree()
+        );
+        assert!(chunks.is_accepting(ChunkId(0)));
+    }
+
+    #[test]
+    fn test_dedup_suffixes_already_shared() {
+        // The graphs created from a matcher already share their tails.
+        let mut chunks = of("[$(1, 2),*]").unwrap();
+        chunks.dedup_suffixes();
+        assert!(chunks == of("[$(1, 2),*]").unwrap());
+    }
+}
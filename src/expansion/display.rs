@@ -0,0 +1,62 @@
+use crate::expansion::{ChunkId, Chunks};
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use core::fmt::Write;
+
+impl Chunks<'_> {
+    /// Render the graph as an indented tree, starting from the first chunks. Chunks reachable
+    /// from multiple parents are only expanded the first time, and then referred to as
+    /// `#id (see above)`.
+    pub(crate) fn display_tree(&self) -> String {
+        let mut output = String::new();
+        let mut printed = BTreeSet::new();
+        for first in &self.firsts {
+            self.display_subtree(*first, 0, &mut printed, &mut output);
+        }
+        output
+    }
+
+    fn display_subtree(
+        &self,
+        id: ChunkId,
+        depth: usize,
+        printed: &mut BTreeSet<ChunkId>,
+        output: &mut String,
+    ) {
+        let indent = "  ".repeat(depth);
+        if !printed.insert(id) {
+            // Writing to a String can't fail.
+            let _ = writeln!(output, "{indent}{id:?} (see above)");
+            return;
+        }
+
+        let chunk = self.get(id);
+        let _ = write!(output, "{indent}{id:?}");
+        for token in &chunk.tokens {
+            let _ = write!(output, " {token}");
+        }
+        output.push('\n');
+        for child in &chunk.childs {
+            self.display_subtree(*child, depth + 1, printed, output);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expansion::of;
+    use insta::assert_snapshot;
+
+    #[test]
+    fn test_display_tree() {
+        assert_snapshot!(of("[$(1),*]").unwrap().display_tree(), @r###"
+        #4 [
+          #0 ]
+          #1 1
+            #0 (see above)
+          #3 1
+            #2 ,
+              #1 (see above)
+        "###);
+    }
+}
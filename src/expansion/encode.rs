@@ -0,0 +1,407 @@
+//! Compact binary encoding of [`Chunks`], to cache expansion graphs without recomputing them.
+//!
+//! The encoding starts with the `PBCH` magic bytes and a version byte, followed by the limit on
+//! the number of chunks, the chunks (each with its role, tokens and children), the first chunks
+//! and the accepting chunks. All integers are LEB128 variable-length integers (zigzag-encoded
+//! when signed), and all lists and strings are prefixed by their length.
+//!
+//! Each token is encoded as a tag byte, followed by the token contents if it has any. Tokens
+//! without contents use their index in [`PUNCTUATION`] as the tag.
+//!
+//! Decoding rejects graphs that couldn't have been created by expanding a source, like chunks
+//! with children allocated after them (which could form cycles) or invalid byte string escapes,
+//! as the rest of the crate relies on those invariants.
+
+use crate::expansion::{Chunk, ChunkId, Chunks, Role};
+use crate::lexer::{unescape_byte_string, Token};
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use anyhow::{bail, ensure, Error};
+
+const MAGIC: &[u8] = b"PBCH";
+const VERSION: u8 = 1;
+
+const PUNCTUATION: &[Token<'static>] = &[
+    Token::OpenParen,
+    Token::CloseParen,
+    Token::OpenSquare,
+    Token::CloseSquare,
+    Token::OpenBrace,
+    Token::CloseBrace,
+    Token::Comma,
+    Token::Plus,
+    Token::Dash,
+    Token::Semicolon,
+    Token::Dollar,
+    Token::Star,
+    Token::StarStar,
+    Token::Slash,
+    Token::Colon,
+    Token::Dot,
+    Token::DotDot,
+    Token::Question,
+    Token::Bang,
+    Token::Ampersand,
+    Token::Pipe,
+    Token::Equals,
+    Token::Less,
+    Token::Greater,
+    Token::Percent,
+    Token::Caret,
+    Token::Tilde,
+    Token::At,
+    Token::Hash,
+    Token::Newline,
+];
+
+const TAG_NUMBER: u8 = 100;
+const TAG_NUMBER_TEXT: u8 = 101;
+const TAG_STRING: u8 = 102;
+const TAG_BYTE_STRING: u8 = 103;
+const TAG_CHAR: u8 = 104;
+const TAG_IDENT: u8 = 105;
+const TAG_UNKNOWN: u8 = 106;
+const TAG_KEYWORD: u8 = 107;
+
+impl<'src> Chunks<'src> {
+    /// Encode the graph in the compact binary format described in the [module docs](self).
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        write_uint(&mut out, self.max_chunks as u64);
+
+        write_uint(&mut out, self.inner.len() as u64);
+        for chunk in &self.inner {
+            out.push(match chunk.role {
+                Role::Delimiter => 0,
+                Role::Content => 1,
+                Role::Separator => 2,
+            });
+            write_uint(&mut out, chunk.tokens.len() as u64);
+            for token in &chunk.tokens {
+                write_token(&mut out, token);
+            }
+            write_ids(&mut out, chunk.childs.iter());
+        }
+
+        write_ids(&mut out, self.firsts.iter());
+        write_ids(&mut out, self.accepting.iter());
+        out
+    }
+
+    /// Decode a graph encoded with [`Chunks::encode`]. Strings in the decoded tokens borrow from
+    /// the encoded bytes, like they would borrow from the source when expanding it. Use
+    /// [`OwnedChunks::decode`](crate::expansion::owned::OwnedChunks::decode) to get a graph
+    /// owning the bytes instead.
+    pub(crate) fn decode(bytes: &'src [u8]) -> Result<Self, Error> {
+        let mut decoder = Decoder { bytes };
+        ensure!(
+            decoder.slice(MAGIC.len())? == MAGIC,
+            "not an encoded chunk graph"
+        );
+        let version = decoder.byte()?;
+        ensure!(
+            version == VERSION,
+            "unsupported chunk graph encoding version {version}"
+        );
+        let max_chunks = usize::try_from(decoder.uint()?).unwrap_or(usize::MAX);
+
+        let len = decoder.len()?;
+        let mut inner = Vec::new();
+        for index in 0..len {
+            let role = match decoder.byte()? {
+                0 => Role::Delimiter,
+                1 => Role::Content,
+                2 => Role::Separator,
+                other => bail!("unknown chunk role {other}"),
+            };
+            let mut tokens = Vec::new();
+            for _ in 0..decoder.len()? {
+                tokens.push(decoder.token()?);
+            }
+            // Children are always allocated before their parents, which guarantees the graph
+            // doesn't have cycles.
+            let childs = decoder.ids(len)?;
+            if let Some(child) = childs.iter().find(|child| child.0 >= index) {
+                bail!(
+                    "chunk #{index} has #{} as a child, which is not allocated before it",
+                    child.0
+                );
+            }
+            inner.push(Chunk {
+                tokens,
+                childs,
+                role,
+            });
+        }
+
+        let firsts = decoder.ids(len)?;
+        let accepting = decoder.ids(len)?.into_iter().collect::<BTreeSet<_>>();
+        ensure!(
+            decoder.bytes.is_empty(),
+            "trailing bytes after the encoded chunk graph"
+        );
+        Ok(Chunks {
+            inner,
+            firsts,
+            accepting,
+            max_chunks,
+        })
+    }
+}
+
+fn write_uint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_int(out: &mut Vec<u8>, value: i64) {
+    write_uint(out, ((value << 1) ^ (value >> 63)) as u64);
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_uint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn write_ids<'a>(out: &mut Vec<u8>, ids: impl ExactSizeIterator<Item = &'a ChunkId>) {
+    write_uint(out, ids.len() as u64);
+    for id in ids {
+        write_uint(out, id.0 as u64);
+    }
+}
+
+fn write_token(out: &mut Vec<u8>, token: &Token<'_>) {
+    if let Some(tag) = PUNCTUATION.iter().position(|p| p == token) {
+        out.push(tag as u8);
+        return;
+    }
+    match token {
+        Token::Number(value) => {
+            out.push(TAG_NUMBER);
+            write_int(out, *value);
+        }
+        Token::NumberText { value, text } => {
+            out.push(TAG_NUMBER_TEXT);
+            write_int(out, *value);
+            write_bytes(out, text.as_bytes());
+        }
+        Token::String(string) => {
+            out.push(TAG_STRING);
+            write_bytes(out, string.as_bytes());
+        }
+        Token::ByteString(bytes) => {
+            out.push(TAG_BYTE_STRING);
+            write_bytes(out, bytes);
+        }
+        Token::Char(c) => {
+            out.push(TAG_CHAR);
+            write_uint(out, *c as u64);
+        }
+        Token::Ident(ident) => {
+            out.push(TAG_IDENT);
+            write_bytes(out, ident.as_bytes());
+        }
+        Token::Keyword(keyword) => {
+            out.push(TAG_KEYWORD);
+            write_bytes(out, keyword.as_bytes());
+        }
+        Token::Unknown(c) => {
+            out.push(TAG_UNKNOWN);
+            write_uint(out, *c as u64);
+        }
+        other => unreachable!("{other:?} is missing from PUNCTUATION"),
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn slice(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        ensure!(
+            self.bytes.len() >= len,
+            "unexpected end of the encoded chunk graph"
+        );
+        let (slice, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        Ok(self.slice(1)?[0])
+    }
+
+    fn uint(&mut self) -> Result<u64, Error> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("integer too large in the encoded chunk graph");
+    }
+
+    fn int(&mut self) -> Result<i64, Error> {
+        let value = self.uint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    /// Decode a length, which can't be larger than the bytes left (as each item takes at least
+    /// one byte). This prevents allocating huge amounts of memory for malformed input.
+    fn len(&mut self) -> Result<usize, Error> {
+        let len = self.uint()?;
+        ensure!(
+            len <= self.bytes.len() as u64,
+            "length {len} is larger than the encoded chunk graph"
+        );
+        Ok(len as usize)
+    }
+
+    fn str(&mut self) -> Result<&'a str, Error> {
+        let len = self.len()?;
+        Ok(core::str::from_utf8(self.slice(len)?)?)
+    }
+
+    fn char(&mut self) -> Result<char, Error> {
+        let value = self.uint()?;
+        match u32::try_from(value).ok().and_then(char::from_u32) {
+            Some(c) => Ok(c),
+            None => bail!("invalid char {value:#x} in the encoded chunk graph"),
+        }
+    }
+
+    fn ids(&mut self, chunks: usize) -> Result<Vec<ChunkId>, Error> {
+        let mut ids = Vec::new();
+        for _ in 0..self.len()? {
+            let id = self.uint()?;
+            ensure!(
+                id < chunks as u64,
+                "chunk #{id} is referenced but doesn't exist"
+            );
+            ids.push(ChunkId(id as usize));
+        }
+        Ok(ids)
+    }
+
+    fn token(&mut self) -> Result<Token<'a>, Error> {
+        let tag = self.byte()?;
+        if let Some(token) = PUNCTUATION.get(usize::from(tag)) {
+            return Ok(*token);
+        }
+        Ok(match tag {
+            TAG_NUMBER => Token::Number(self.int()?),
+            TAG_NUMBER_TEXT => Token::NumberText {
+                value: self.int()?,
+                text: self.str()?,
+            },
+            TAG_STRING => Token::String(self.str()?),
+            TAG_BYTE_STRING => {
+                let len = self.len()?;
+                let raw = self.slice(len)?;
+                unescape_byte_string(raw)?;
+                Token::ByteString(raw)
+            }
+            TAG_CHAR => Token::Char(self.char()?),
+            TAG_IDENT => Token::Ident(self.str()?),
+            TAG_UNKNOWN => Token::Unknown(self.char()?),
+            TAG_KEYWORD => Token::Keyword(self.str()?),
+            other => bail!("unknown token tag {other}"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expansion::of;
+
+    #[test]
+    fn test_round_trip() {
+        let source = r#"foo! { $(["a" 'b' b"\xff" -1 ** $($x:ident);*]),* } @"#;
+        let chunks = of(source).unwrap();
+        let encoded = chunks.encode();
+        let decoded = Chunks::decode(&encoded).unwrap();
+        assert_eq!(chunks.display_tree(), decoded.display_tree());
+        assert_eq!(chunks, decoded);
+    }
+
+    #[test]
+    fn test_round_trip_lexer_options() {
+        // Tokens only produced with some lexer options enabled.
+        let mut chunks = of("if [$(1),*]").unwrap();
+        chunks.map_tokens(|token| match *token {
+            Token::Number(value) => {
+                *token = Token::NumberText {
+                    value: value - i64::MAX,
+                    text: "0001",
+                }
+            }
+            Token::Ident(ident) => *token = Token::Keyword(ident),
+            _ => {}
+        });
+        let encoded = chunks.encode();
+        assert_eq!(chunks, Chunks::decode(&encoded).unwrap());
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        let encoded = of("[$(1),*]").unwrap().encode();
+        let decode = |bytes: &[u8]| Chunks::decode(bytes).err().unwrap().to_string();
+
+        assert_eq!("not an encoded chunk graph", decode(b"JSON{}"));
+        assert_eq!(
+            "unexpected end of the encoded chunk graph",
+            decode(&encoded[..MAGIC.len()])
+        );
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert_eq!(
+            "trailing bytes after the encoded chunk graph",
+            decode(&trailing)
+        );
+        let mut version = encoded.clone();
+        version[MAGIC.len()] = 2;
+        assert_eq!(
+            "unsupported chunk graph encoding version 2",
+            decode(&version)
+        );
+    }
+
+    /// Encode a graph with a single chunk, with the provided encoded tokens and children.
+    fn single_chunk(tokens: &[u8], childs: &[u8]) -> Vec<u8> {
+        let mut encoded = MAGIC.to_vec();
+        encoded.extend_from_slice(&[VERSION, 10, 1, 0]);
+        encoded.extend_from_slice(tokens);
+        encoded.extend_from_slice(childs);
+        // The first chunk is #0, and no chunks are accepting.
+        encoded.extend_from_slice(&[1, 0, 0]);
+        encoded
+    }
+
+    #[test]
+    fn test_decode_invalid_graph() {
+        let decode = |bytes: &[u8]| Chunks::decode(bytes).err().unwrap().to_string();
+
+        assert!(Chunks::decode(&single_chunk(&[0], &[0])).is_ok());
+        assert_eq!(
+            "chunk #0 has #0 as a child, which is not allocated before it",
+            decode(&single_chunk(&[0], &[1, 0]))
+        );
+        assert_eq!(
+            "unknown escape in byte string: \\q",
+            decode(&single_chunk(&[1, TAG_BYTE_STRING, 2, b'\\', b'q'], &[0]))
+        );
+    }
+}
@@ -0,0 +1,149 @@
+use crate::expansion::Chunks;
+use crate::lexer::Token;
+use alloc::vec;
+
+impl Chunks<'_> {
+    /// Fold constant integer additions and subtractions (like `1 + 2`) into a single number.
+    ///
+    /// Folding is conservative: it's only done from left to right, and only when it can't change
+    /// the meaning of the expression, so for example `x - 1 + 2` and `1 + 2 * 3` are left as-is.
+    /// This also takes into account the tokens in the chunks before and after each chunk.
+    pub(crate) fn fold_constants(&mut self) {
+        // Whether any of the chunks leading to each chunk ends with a token that can't precede a
+        // folded addition or subtraction.
+        let mut after_disallowed = vec![false; self.inner.len()];
+        // Whether any of the chunks following each chunk starts with a token that can't follow a
+        // folded addition or subtraction.
+        let mut before_disallowed = vec![false; self.inner.len()];
+
+        for (idx, chunk) in self.inner.iter().enumerate() {
+            let ends_disallowed = !chunk.tokens.last().is_some_and(may_precede_fold);
+            for child in &chunk.childs {
+                after_disallowed[child.0] |= ends_disallowed;
+                before_disallowed[idx] |= !self.inner[child.0]
+                    .tokens
+                    .first()
+                    .is_some_and(may_follow_fold);
+            }
+        }
+
+        for (idx, chunk) in self.inner.iter_mut().enumerate() {
+            let mut i = 0;
+            while i + 3 <= chunk.tokens.len() {
+                let preceded_by_disallowed = match i {
+                    0 => after_disallowed[idx],
+                    _ => !may_precede_fold(&chunk.tokens[i - 1]),
+                };
+                let followed_by_disallowed = match chunk.tokens.get(i + 3) {
+                    None => before_disallowed[idx],
+                    Some(next) => !may_follow_fold(next),
+                };
+
+                let folded = match &chunk.tokens[i..i + 3] {
+                    _ if preceded_by_disallowed || followed_by_disallowed => None,
+                    [Token::Number(a), Token::Plus, Token::Number(b)] => a.checked_add(*b),
+                    [Token::Number(a), Token::Dash, Token::Number(b)] => a.checked_sub(*b),
+                    _ => None,
+                };
+                match folded {
+                    // Don't advance, as the result could be folded with the following tokens.
+                    Some(result) => {
+                        chunk.tokens.splice(i..i + 3, [Token::Number(result)]);
+                    }
+                    None => i += 1,
+                }
+            }
+        }
+    }
+}
+
+/// Whether the token can come right before a folded addition or subtraction without changing
+/// its meaning. This only allows opening delimiters, separators and operators binding looser
+/// than `+` and `-`, so unknown or new tokens prevent folding.
+fn may_precede_fold(token: &Token<'_>) -> bool {
+    matches!(
+        token,
+        Token::OpenParen
+            | Token::OpenSquare
+            | Token::OpenBrace
+            | Token::Comma
+            | Token::Semicolon
+            | Token::Equals
+            | Token::Less
+            | Token::Greater
+            | Token::Pipe
+            | Token::Caret
+            | Token::DotDot
+    )
+}
+
+/// Whether the token can come right after a folded addition or subtraction without changing its
+/// meaning. Besides the tokens allowed before it, `+` and `-` are allowed as folding happens left
+/// to right, but opening delimiters aren't as they could start a call or an index.
+fn may_follow_fold(token: &Token<'_>) -> bool {
+    matches!(
+        token,
+        Token::CloseParen
+            | Token::CloseSquare
+            | Token::CloseBrace
+            | Token::Comma
+            | Token::Semicolon
+            | Token::Equals
+            | Token::Less
+            | Token::Greater
+            | Token::Ampersand
+            | Token::Pipe
+            | Token::Caret
+            | Token::DotDot
+            | Token::Plus
+            | Token::Dash
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expansion::of;
+    use crate::lexer::Token;
+
+    #[test]
+    fn test_fold_constants() {
+        let mut chunks = of("$(1 + 2 - 4),*").unwrap();
+        chunks.fold_constants();
+
+        for chunk in &chunks.inner {
+            assert!(matches!(
+                chunk.tokens(),
+                [Token::Number(-1)] | [Token::Comma]
+            ));
+        }
+    }
+
+    #[test]
+    fn test_fold_constants_between_delimiters() {
+        let mut chunks = of("[(1 + 2), 3 - 1 + 4; x = 2 - 1]").unwrap();
+        chunks.fold_constants();
+        assert_eq!(of("[(3), 6; x = 1]").unwrap(), chunks);
+    }
+
+    #[test]
+    fn test_fold_constants_conservative() {
+        for input in [
+            "x - 1 + 2",
+            "1 + 2 * 3",
+            "[x - $(1 + 2),*]",
+            "[1 + 2 $(* 3)*]",
+            "1 + 2 % 3",
+            "!1 + 2",
+            "~1 + 2",
+            "x.1 + 2",
+            "1 + 2?",
+            "&1 + 2",
+            "[1 + 2 $(. 3)*]",
+        ] {
+            let mut chunks = of(input).unwrap();
+            let original = of(input).unwrap();
+            chunks.fold_constants();
+            assert_eq!(original, chunks, "{input} should not be folded");
+        }
+    }
+}
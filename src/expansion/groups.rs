@@ -1,11 +1,12 @@
 use crate::expansion::tree::TokenTree;
 use crate::lexer::Token;
-use std::mem::take;
+use alloc::vec::Vec;
+use core::mem::take;
 
 /// [`Group`] propagates repetitions as-is from [`TokenTree`], and collapses multiple
 /// [`TokenTree`]s without repetitions into a single element (the "group").
 #[derive(Debug, Clone)]
-pub(super) enum Group<'src> {
+pub(crate) enum Group<'src> {
     Simple(Vec<Token<'src>>),
     Repetition {
         content: Vec<Group<'src>>,
@@ -20,6 +21,13 @@ pub(super) fn create_groups(stream: Vec<TokenTree<'_>>) -> Vec<Group<'_>> {
     for tree in stream {
         match tree {
             TokenTree::Token(token) => current_simple.push(token),
+            // The chunk graph doesn't know about metavariables, so keep them as plain tokens.
+            TokenTree::Metavariable { name, kind } => {
+                current_simple.extend([Token::Dollar, Token::Ident(name)]);
+                if let Some(kind) = kind {
+                    current_simple.extend([Token::Colon, Token::Ident(kind)]);
+                }
+            }
             TokenTree::Repetition(repetition) => {
                 if !current_simple.is_empty() {
                     result.push(Group::Simple(take(&mut current_simple)));
@@ -49,7 +57,7 @@ mod tests {
     #[test]
     fn test_create_groups() {
         let input = "[$(1, $(3,)*,),*]";
-        let stream = parse_tokenstream(Lexer::new(input).collect()).unwrap();
+        let stream = parse_tokenstream(Lexer::tokenize_all(input).unwrap()).unwrap();
 
         let groups = create_groups(stream);
         assert_debug_snapshot!(groups, @r###"
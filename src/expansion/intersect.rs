@@ -0,0 +1,86 @@
+use crate::expansion::{ChunkId, Chunks};
+use alloc::collections::BTreeSet;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Position inside of a graph: the chunk and the index of the next token in it.
+pub(super) type Position = (ChunkId, usize);
+
+impl Chunks<'_> {
+    /// Whether there is at least one token sequence matched by both graphs. This walks both
+    /// graphs in lockstep (like the product of two automata), following only the paths where
+    /// the tokens agree.
+    pub(crate) fn intersects(&self, other: &Chunks<'_>) -> bool {
+        // A graph without first chunks doesn't match anything, not even the empty sequence.
+        if self.firsts.is_empty() || other.firsts.is_empty() {
+            return false;
+        }
+
+        let mut visited = BTreeSet::new();
+        let mut queue = Vec::new();
+        for ours in &self.firsts {
+            for theirs in &other.firsts {
+                queue.push(((*ours, 0), (*theirs, 0)));
+            }
+        }
+
+        while let Some((ours, theirs)) = queue.pop() {
+            if !visited.insert((ours, theirs)) {
+                continue;
+            }
+            if self.get(ours.0).tokens[ours.1] != other.get(theirs.0).tokens[theirs.1] {
+                continue;
+            }
+            // `None` means the match can end after the current token.
+            for next_ours in self.advance(ours) {
+                for next_theirs in other.advance(theirs) {
+                    match (next_ours, next_theirs) {
+                        (None, None) => return true,
+                        (Some(ours), Some(theirs)) => queue.push((ours, theirs)),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    /// Return the positions following the current one, including `None` if the match can end
+    /// after the current token.
+    pub(super) fn advance(&self, (id, index): Position) -> Vec<Option<Position>> {
+        let chunk = self.get(id);
+        if index + 1 < chunk.tokens.len() {
+            return vec![Some((id, index + 1))];
+        }
+        let mut next = chunk
+            .childs
+            .iter()
+            .map(|child| Some((*child, 0)))
+            .collect::<Vec<_>>();
+        if self.is_accepting(id) {
+            next.push(None);
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expansion::of;
+
+    #[test]
+    fn test_intersects() {
+        let intersects = |a, b| of(a).unwrap().intersects(&of(b).unwrap());
+
+        assert!(intersects("[$(1),*]", "[1]"));
+        assert!(intersects("[1]", "[$(1),*]"));
+        assert!(intersects("[$(1),*]", "[$(1);*]"));
+        assert!(intersects("$(a)* $(a b)* c", "a b c"));
+        assert!(!intersects("[$(1),*]", "[2]"));
+        assert!(!intersects("[$(1),*]", "[1, 1"));
+        assert!(!intersects("$(1 2),*", "1 2 1 2"));
+        assert!(!intersects("", ""));
+        assert!(!intersects("", "[1]"));
+        assert!(!intersects("[1]", ""));
+    }
+}
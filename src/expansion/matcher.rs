@@ -0,0 +1,261 @@
+use crate::expansion::intersect::Position;
+use crate::expansion::{ChunkId, Chunks};
+use crate::lexer::Token;
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use anyhow::{bail, Error};
+
+impl Chunks<'_> {
+    /// Whether a path through the graph consumes exactly the provided tokens, ending in an accept
+    /// state.
+    pub(crate) fn matches(&self, tokens: &[Token<'_>]) -> bool {
+        self.match_capture(tokens).is_some()
+    }
+
+    /// Same as [`Chunks::matches`], but return the chunks making up the matching path.
+    pub(crate) fn match_capture(&self, tokens: &[Token<'_>]) -> Option<Vec<ChunkId>> {
+        self.match_capture_with_budget(tokens, None)
+            .expect("matching without a budget can't fail")
+    }
+
+    /// Same as [`Chunks::match_capture`], but abort with an error after trying `max_steps`
+    /// chunks, to avoid spending too long on adversarial graphs or inputs.
+    pub(crate) fn match_capture_with_budget(
+        &self,
+        tokens: &[Token<'_>],
+        max_steps: Option<usize>,
+    ) -> Result<Option<Vec<ChunkId>>, Error> {
+        let mut matcher = Matcher {
+            chunks: self,
+            path: Vec::new(),
+            failed: BTreeSet::new(),
+            steps: 0,
+            max_steps,
+        };
+        Ok(matcher
+            .match_from(&self.firsts, tokens)?
+            .then_some(matcher.path))
+    }
+
+    /// Explain why the tokens are not matched by the graph, returning `None` if they are. All
+    /// paths through the graph are followed at the same time, and the mismatch is reported at the
+    /// first token none of them accepts.
+    pub(crate) fn explain_mismatch(&self, tokens: &[Token<'_>]) -> Option<Mismatch<'_>> {
+        // `None` means the match can end before the current token.
+        let mut current = self
+            .firsts
+            .iter()
+            .map(|first| Some((*first, 0)))
+            .collect::<BTreeSet<Option<Position>>>();
+        for (position, token) in tokens.iter().enumerate() {
+            let next = current
+                .iter()
+                .flatten()
+                .filter(|(id, index)| self.get(*id).tokens[*index] == *token)
+                .flat_map(|position| self.advance(*position))
+                .collect::<BTreeSet<_>>();
+            if next.is_empty() {
+                return Some(self.mismatch_at(position, &current));
+            }
+            current = next;
+        }
+        if current.contains(&None) {
+            None
+        } else {
+            Some(self.mismatch_at(tokens.len(), &current))
+        }
+    }
+
+    fn mismatch_at(&self, position: usize, current: &BTreeSet<Option<Position>>) -> Mismatch<'_> {
+        Mismatch {
+            position,
+            expected: current
+                .iter()
+                .flatten()
+                .map(|(id, index)| self.get(*id).tokens[*index])
+                .collect(),
+            end_allowed: current.contains(&None),
+        }
+    }
+}
+
+/// Why a token sequence is not matched by the graph, returned by [`Chunks::explain_mismatch`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Mismatch<'src> {
+    /// Index of the first token not accepted by any path, or the number of tokens if the input
+    /// ended too early.
+    pub(crate) position: usize,
+    /// Tokens that would have been accepted at the position.
+    pub(crate) expected: BTreeSet<Token<'src>>,
+    /// Whether the input could have ended at the position.
+    pub(crate) end_allowed: bool,
+}
+
+/// Depth-first search of a path matching the tokens, backtracking when a path fails.
+struct Matcher<'a, 'src> {
+    chunks: &'a Chunks<'src>,
+    path: Vec<ChunkId>,
+    /// Chunks already known not to lead to a match, along with how many tokens were left when
+    /// they were tried. As different paths can reach the same chunk at the same point of the
+    /// input, this prevents the search from becoming exponential on ambiguous graphs.
+    failed: BTreeSet<(ChunkId, usize)>,
+    steps: usize,
+    max_steps: Option<usize>,
+}
+
+impl Matcher<'_, '_> {
+    fn match_from(&mut self, candidates: &[ChunkId], tokens: &[Token<'_>]) -> Result<bool, Error> {
+        for id in candidates {
+            if self.failed.contains(&(*id, tokens.len())) {
+                continue;
+            }
+            if let Some(max) = self.max_steps {
+                if self.steps >= max {
+                    bail!("exceeded the maximum of {max} matching steps");
+                }
+            }
+            self.steps += 1;
+
+            let chunk = self.chunks.get(*id);
+            let Some(rest) = tokens.strip_prefix(chunk.tokens.as_slice()) else {
+                continue;
+            };
+            self.path.push(*id);
+            if rest.is_empty() && self.chunks.is_accepting(*id) {
+                return Ok(true);
+            }
+            // Multiple children can start with the same tokens (like the instances of a
+            // repetition), so if this path doesn't match try the next candidate.
+            if self.match_from(&chunk.childs, rest)? {
+                return Ok(true);
+            }
+            self.path.pop();
+            self.failed.insert((*id, tokens.len()));
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expansion::of;
+    use crate::lexer::Lexer;
+
+    #[test]
+    fn test_matches() {
+        let chunks = of("[$(1),*]").unwrap();
+        let matches = |input| chunks.matches(&Lexer::tokenize_all(input).unwrap());
+
+        assert!(matches("[]"));
+        assert!(matches("[1]"));
+        assert!(matches("[1, 1]"));
+        assert!(!matches("[1 1]"));
+        assert!(!matches("[1,]"));
+        assert!(!matches("[1, 1"));
+    }
+
+    #[test]
+    fn test_matches_ambiguous() {
+        // Matching the first `a` with the first repetition is only correct for some inputs.
+        let chunks = of("$(a)* $(a b)* c").unwrap();
+        let matches = |input| chunks.matches(&Lexer::tokenize_all(input).unwrap());
+        assert!(matches("a a c"));
+        assert!(matches("a b c"));
+        assert!(matches("a a b c"));
+        assert!(matches("a a b a b c"));
+        assert!(!matches("a b a c"));
+    }
+
+    #[test]
+    fn test_matches_many_ambiguous_paths() {
+        // Each repetition can consume zero, one or two tokens, so there are a lot of ways to
+        // split the input between them, none of which matches.
+        let matcher = "$(1)* ".repeat(20);
+        let chunks = of(&matcher).unwrap();
+        let mut tokens = vec![Token::Number(1); 40];
+        assert!(chunks.matches(&tokens));
+        tokens.push(Token::Number(2));
+        assert!(!chunks.matches(&tokens));
+    }
+
+    #[test]
+    fn test_match_budget() {
+        let matcher = "$(1)* ".repeat(20);
+        let chunks = of(&matcher).unwrap();
+        let mut tokens = vec![Token::Number(1); 40];
+        tokens.push(Token::Number(2));
+
+        let err = chunks
+            .match_capture_with_budget(&tokens, Some(100))
+            .unwrap_err();
+        assert_eq!(
+            "exceeded the maximum of 100 matching steps",
+            err.to_string()
+        );
+
+        let tokens = Lexer::tokenize_all("1 1").unwrap();
+        assert!(chunks
+            .match_capture_with_budget(&tokens, Some(100))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_explain_mismatch() {
+        let chunks = of("[$(1),*]").unwrap();
+        let explain = |input| chunks.explain_mismatch(&Lexer::tokenize_all(input).unwrap());
+
+        assert_eq!(None, explain("[1, 1]"));
+        assert_eq!(
+            Some(Mismatch {
+                position: 2,
+                expected: BTreeSet::from([Token::Comma, Token::CloseSquare]),
+                end_allowed: false,
+            }),
+            explain("[1 1]")
+        );
+        assert_eq!(
+            Some(Mismatch {
+                position: 3,
+                expected: BTreeSet::from([Token::Number(1)]),
+                end_allowed: false,
+            }),
+            explain("[1,]")
+        );
+        // The input ended too early.
+        assert_eq!(
+            Some(Mismatch {
+                position: 4,
+                expected: BTreeSet::from([Token::CloseSquare]),
+                end_allowed: false,
+            }),
+            explain("[1, 1")
+        );
+        // The input continues after the end of the expansion.
+        assert_eq!(
+            Some(Mismatch {
+                position: 2,
+                expected: BTreeSet::new(),
+                end_allowed: true,
+            }),
+            explain("[] 1")
+        );
+    }
+
+    #[test]
+    fn test_match_capture() {
+        let chunks = of("[$(1),*]").unwrap();
+        let tokens = Lexer::tokenize_all("[1, 1]").unwrap();
+        assert_eq!(
+            Some(vec![
+                ChunkId(4),
+                ChunkId(3),
+                ChunkId(2),
+                ChunkId(1),
+                ChunkId(0)
+            ]),
+            chunks.match_capture(&tokens)
+        );
+    }
+}
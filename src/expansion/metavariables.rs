@@ -0,0 +1,96 @@
+use crate::expansion::tree::{parse_tokenstream, TokenTree};
+use crate::lexer::Lexer;
+use alloc::collections::BTreeMap;
+use anyhow::{bail, Error};
+
+/// A metavariable (like `$name:expr`) bound by a matcher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Metavariable<'src> {
+    /// Fragment kind of the metavariable, if any occurrence specified it.
+    pub(crate) kind: Option<&'src str>,
+    /// How many repetitions the metavariable is nested in.
+    pub(crate) depth: usize,
+}
+
+/// Collect the metavariables used in `input`, keyed by their name. Occurrences of the same
+/// metavariable without a fragment kind are allowed, but an error is returned if two of them
+/// specify a different kind.
+pub(crate) fn metavariables(input: &str) -> Result<BTreeMap<&str, Metavariable<'_>>, Error> {
+    let tokens = Lexer::tokenize_all(input)?;
+    let trees = parse_tokenstream(tokens)?;
+
+    let mut found = BTreeMap::new();
+    collect(&trees, 0, &mut found)?;
+    Ok(found)
+}
+
+fn collect<'src>(
+    trees: &[TokenTree<'src>],
+    depth: usize,
+    found: &mut BTreeMap<&'src str, Metavariable<'src>>,
+) -> Result<(), Error> {
+    for tree in trees {
+        match tree {
+            TokenTree::Token(_) => {}
+            TokenTree::Repetition(repetition) => collect(&repetition.repeated, depth + 1, found)?,
+            TokenTree::Metavariable { name, kind } => {
+                let existing = found
+                    .entry(*name)
+                    .or_insert(Metavariable { kind: *kind, depth });
+                match (existing.kind, kind) {
+                    (Some(existing), Some(kind)) if existing != *kind => {
+                        bail!("metavariable `${name}` is used as both `{existing}` and `{kind}`")
+                    }
+                    (None, Some(kind)) => existing.kind = Some(kind),
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metavariables() {
+        let found = metavariables("$($x $y:expr),* $z").unwrap();
+        assert_eq!(
+            vec![
+                (
+                    "x",
+                    Metavariable {
+                        kind: None,
+                        depth: 1
+                    }
+                ),
+                (
+                    "y",
+                    Metavariable {
+                        kind: Some("expr"),
+                        depth: 1
+                    }
+                ),
+                (
+                    "z",
+                    Metavariable {
+                        kind: None,
+                        depth: 0
+                    }
+                ),
+            ],
+            found.into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_metavariables_conflicting_kinds() {
+        let err = metavariables("$x:expr $x $x:ident").unwrap_err();
+        assert_eq!(
+            "metavariable `$x` is used as both `expr` and `ident`",
+            err.to_string()
+        );
+    }
+}
@@ -1,25 +1,66 @@
+mod dedup;
+mod display;
+mod encode;
+mod fold;
 mod groups;
+mod intersect;
+mod matcher;
+mod metavariables;
+mod owned;
+mod pattern;
 mod tree;
 
-use crate::expansion::groups::{create_groups, Group};
+use crate::expansion::groups::create_groups;
+pub(crate) use crate::expansion::groups::Group;
 use crate::expansion::tree::{parse_tokenstream, TokenTree};
 use crate::lexer::{Lexer, Token};
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
 use anyhow::{anyhow, bail, ensure, Error};
-use std::mem::take;
+use core::mem::take;
 
+#[derive(PartialEq)]
 pub(crate) struct Chunks<'src> {
     inner: Vec<Chunk<'src>>,
     firsts: Vec<ChunkId>,
+    accepting: BTreeSet<ChunkId>,
+    max_chunks: usize,
 }
 
 impl<'src> Chunks<'src> {
-    fn new() -> Self {
+    fn new(max_chunks: usize) -> Self {
         Self {
             inner: Vec::new(),
             firsts: Vec::new(),
+            accepting: BTreeSet::new(),
+            max_chunks,
         }
     }
 
+    /// Build the chunk graph out of already created [`Group`]s, without having to lex and parse
+    /// a string first.
+    pub(crate) fn from_groups(groups: Vec<Group<'src>>) -> Result<Self, Error> {
+        Self::from_groups_with_limit(groups, usize::MAX)
+    }
+
+    /// Same as [`Chunks::from_groups`], but return an error if more than `max_chunks` chunks
+    /// would need to be created.
+    pub(crate) fn from_groups_with_limit(
+        groups: Vec<Group<'src>>,
+        max_chunks: usize,
+    ) -> Result<Self, Error> {
+        let mut chunks = Chunks::new(max_chunks);
+        chunks.firsts = create_chunks(&mut chunks, groups, Vec::new(), Role::Delimiter)?;
+        chunks.accepting = (0..chunks.inner.len())
+            .map(ChunkId)
+            .filter(|id| chunks.get(*id).childs.is_empty())
+            .collect();
+        Ok(chunks)
+    }
+
     pub(crate) fn get(&self, id: ChunkId) -> &Chunk<'src> {
         &self.inner[id.0]
     }
@@ -28,56 +69,335 @@ impl<'src> Chunks<'src> {
         self.firsts.iter().map(|id| self.get(*id))
     }
 
-    fn allocate(&mut self, chunk: Chunk<'src>) -> ChunkId {
+    /// Whether a match can end after the chunk. By default only the chunks without children are
+    /// accept states.
+    pub(crate) fn is_accepting(&self, id: ChunkId) -> bool {
+        self.accepting.contains(&id)
+    }
+
+    /// Mark whether a match can end after the chunk, for example to also accept prefixes of
+    /// the expansion.
+    pub(crate) fn set_accepting(&mut self, id: ChunkId, accepting: bool) {
+        if accepting {
+            self.accepting.insert(id);
+        } else {
+            self.accepting.remove(&id);
+        }
+    }
+
+    /// Whether the graph matches no token sequence at all, because no accepting chunk can be
+    /// reached from the first chunks. This is the case for a graph without any chunk, but also
+    /// for graphs whose paths never end (for example after the accepting chunks were unset).
+    pub(crate) fn is_empty_language(&self) -> bool {
+        !self.firsts.iter().any(|first| {
+            self.reachable(*first)
+                .iter()
+                .any(|id| self.is_accepting(*id))
+        })
+    }
+
+    /// Apply a transformation to every token in every chunk, without changing the structure of
+    /// the graph.
+    pub(crate) fn map_tokens<F: FnMut(&mut Token<'src>)>(&mut self, mut f: F) {
+        for chunk in &mut self.inner {
+            chunk.tokens.iter_mut().for_each(&mut f);
+        }
+    }
+
+    /// Return, for each depth in the graph, the set of tokens that can appear in the chunks at
+    /// that depth. The depth of a chunk is its shortest distance from one of the first chunks.
+    pub(crate) fn tokens_at_depth(&self) -> Vec<BTreeSet<Token<'src>>> {
+        self.bfs_levels()
+            .into_iter()
+            .map(|level| {
+                level
+                    .into_iter()
+                    .flat_map(|id| self.get(id).tokens.iter().copied())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Group the chunks by their shortest distance from one of the first chunks, for example to
+    /// render the graph in layers. Chunks are sorted by ID within each level, and chunks not
+    /// reachable from the first chunks are not included.
+    pub(crate) fn bfs_levels(&self) -> Vec<Vec<ChunkId>> {
+        let mut visited = BTreeSet::new();
+        let mut levels = Vec::new();
+        let mut current = self.firsts.clone();
+        visited.extend(current.iter().copied());
+        while !current.is_empty() {
+            current.sort();
+            let mut next = Vec::new();
+            for id in &current {
+                for child in &self.get(*id).childs {
+                    if visited.insert(*child) {
+                        next.push(*child);
+                    }
+                }
+            }
+            levels.push(current);
+            current = next;
+        }
+        levels
+    }
+
+    /// Return all the distinct separators between instances of repetitions in the graph, for
+    /// example to check that a matcher separates its repetitions consistently.
+    pub(crate) fn collect_separators(&self) -> BTreeSet<Token<'src>> {
+        self.inner
+            .iter()
+            .filter(|chunk| chunk.role == Role::Separator)
+            .flat_map(|chunk| chunk.tokens.iter().copied())
+            .collect()
+    }
+
+    /// Walk every path through the graph depth-first, invoking the visitor when entering and
+    /// leaving each chunk. A chunk shared by multiple paths is entered once for each of them.
+    pub(crate) fn walk_with<F>(&self, mut visitor: F)
+    where
+        F: FnMut(WalkEvent),
+    {
+        for first in &self.firsts {
+            self.walk_from(*first, &mut visitor);
+        }
+    }
+
+    fn walk_from<F>(&self, id: ChunkId, visitor: &mut F)
+    where
+        F: FnMut(WalkEvent),
+    {
+        visitor(WalkEvent::Enter(id));
+        for child in &self.get(id).childs {
+            self.walk_from(*child, visitor);
+        }
+        visitor(WalkEvent::Leave(id));
+    }
+
+    /// Iterate over all the edges of the graph, as `(parent, child)` pairs.
+    pub(crate) fn edges(&self) -> impl Iterator<Item = (ChunkId, ChunkId)> + '_ {
+        self.inner
+            .iter()
+            .enumerate()
+            .flat_map(|(id, chunk)| chunk.childs.iter().map(move |child| (ChunkId(id), *child)))
+    }
+
+    /// Return one of the shortest token sequences matched by the graph, or `None` if the graph
+    /// doesn't match anything.
+    pub(crate) fn sample_shortest(&self) -> Option<Vec<Token<'src>>> {
+        let min_tokens = self.min_tokens();
+        let shortest = |ids: &[ChunkId]| {
+            ids.iter()
+                .filter_map(|id| Some((min_tokens[id.0]?, *id)))
+                .min()
+                .map(|(_, id)| id)
+        };
+
+        let mut sample = Vec::new();
+        let mut current = shortest(&self.firsts)?;
+        loop {
+            let chunk = self.get(current);
+            sample.extend(chunk.tokens.iter().copied());
+            if self.is_accepting(current) && min_tokens[current.0] == Some(chunk.tokens.len()) {
+                return Some(sample);
+            }
+            current = shortest(&chunk.childs)?;
+        }
+    }
+
+    /// Return a rough summary of the size of the graph, cheap to compute even when enumerating
+    /// all of its expansions wouldn't be. Useful to show progress or warn about huge graphs.
+    pub(crate) fn approximate_size_hint(&self) -> SizeHint {
+        let counts = self.count_expansions();
+        SizeHint {
+            chunks: self.inner.len(),
+            edges: self.edges().count(),
+            expansions: self
+                .firsts
+                .iter()
+                .fold(0, |total: u64, first| total.saturating_add(counts[first.0])),
+        }
+    }
+
+    /// Return, for each chunk, how many different paths lead from it to the end of a match,
+    /// saturating at [`u64::MAX`].
+    fn count_expansions(&self) -> Vec<u64> {
+        let mut counts: Vec<u64> = Vec::with_capacity(self.inner.len());
+        // Children are always allocated before their parents, so they were already computed.
+        for (id, chunk) in self.inner.iter().enumerate() {
+            let end = u64::from(self.is_accepting(ChunkId(id)));
+            let count = chunk
+                .childs
+                .iter()
+                .fold(end, |count, child| count.saturating_add(counts[child.0]));
+            counts.push(count);
+        }
+        counts
+    }
+
+    /// Return, for each chunk, the minimum number of tokens from the start of the chunk to the
+    /// end of a match, or `None` if no accepting chunk can be reached from it.
+    fn min_tokens(&self) -> Vec<Option<usize>> {
+        let mut min_tokens: Vec<Option<usize>> = Vec::with_capacity(self.inner.len());
+        // Children are always allocated before their parents, so they were already computed.
+        for (id, chunk) in self.inner.iter().enumerate() {
+            let end = self.is_accepting(ChunkId(id)).then_some(0);
+            let rest = chunk.childs.iter().filter_map(|child| min_tokens[child.0]);
+            min_tokens.push(
+                end.into_iter()
+                    .chain(rest)
+                    .min()
+                    .map(|rest| rest + chunk.tokens.len()),
+            );
+        }
+        min_tokens
+    }
+
+    fn allocate(&mut self, chunk: Chunk<'src>) -> Result<ChunkId, Error> {
+        ensure!(
+            self.inner.len() < self.max_chunks,
+            "expansion requires more than {} chunks",
+            self.max_chunks
+        );
         let id = ChunkId(self.inner.len());
         self.inner.push(chunk);
-        id
+        Ok(id)
     }
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
 pub(crate) struct ChunkId(usize);
 
+/// Summary of the size of a graph, returned by [`Chunks::approximate_size_hint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SizeHint {
+    pub(crate) chunks: usize,
+    pub(crate) edges: usize,
+    /// How many different token sequences the graph expands to, saturating at [`u64::MAX`].
+    /// Paths producing the same tokens are counted separately.
+    pub(crate) expansions: u64,
+}
+
+/// Event emitted by [`Chunks::walk_with`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum WalkEvent {
+    Enter(ChunkId),
+    Leave(ChunkId),
+}
+
 #[derive(Clone, PartialEq)]
 pub(crate) struct Chunk<'src> {
-    pub(crate) tokens: Vec<Token<'src>>,
-    pub(crate) childs: Vec<ChunkId>,
+    tokens: Vec<Token<'src>>,
+    childs: Vec<ChunkId>,
+    role: Role,
+}
+
+/// Why a [`Chunk`] is part of the graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Role {
+    /// Tokens outside of any repetition, which are emitted exactly once.
+    Delimiter,
+    /// Tokens inside of a repetition.
+    Content,
+    /// The separator between two instances of a repetition.
+    Separator,
 }
 
-// Warning: this does not check for delimiter balancing.
+impl<'src> Chunk<'src> {
+    /// Tokens contained in this chunk, which are always emitted together.
+    pub(crate) fn tokens(&self) -> &[Token<'src>] {
+        &self.tokens
+    }
+
+    /// Chunks that can follow this one. An empty list means the chunk is the last one.
+    pub(crate) fn children(&self) -> &[ChunkId] {
+        &self.childs
+    }
+
+    pub(crate) fn role(&self) -> Role {
+        self.role
+    }
+}
+
+// Warning: this does not check for delimiter balancing, see `of_repaired` to balance them.
 pub(super) fn of(input: &str) -> Result<Chunks, Error> {
-    let tokens = Lexer::new(input).collect::<Vec<_>>();
+    let tokens = Lexer::tokenize_all(input)?;
 
     let token_stream = parse_tokenstream(tokens)?;
     let groups = create_groups(token_stream);
 
-    let mut chunks = Chunks::new();
-    let result = create_chunks(&mut chunks, groups, Vec::new());
-    chunks.firsts = result;
+    Chunks::from_groups(groups)
+}
+
+/// Same as [`of`], but balance the delimiters in the input before expanding it, for tooling that
+/// needs a best-effort graph out of input being edited. Missing closing delimiters are inserted
+/// and unmatched ones are removed, returning a diagnostic for each of those repairs.
+pub(super) fn of_repaired(input: &str) -> Result<(Chunks<'_>, Vec<String>), Error> {
+    let tokens = Lexer::tokenize_all(input)?;
+    let (tokens, diagnostics) = balance_delimiters(tokens);
+
+    let token_stream = parse_tokenstream(tokens)?;
+    let groups = create_groups(token_stream);
 
-    Ok(chunks)
+    Ok((Chunks::from_groups(groups)?, diagnostics))
+}
+
+fn balance_delimiters(tokens: Vec<Token>) -> (Vec<Token>, Vec<String>) {
+    let mut balanced = Vec::with_capacity(tokens.len());
+    let mut diagnostics = Vec::new();
+    let mut expected_closes = Vec::new();
+    for (position, token) in tokens.into_iter().enumerate() {
+        if let Some(close) = token.matching_close() {
+            expected_closes.push(close);
+        } else if token.is_close_delimiter() {
+            // Close the delimiters opened after the one matching this token, or drop the token
+            // if no delimiter it could close was opened.
+            let Some(depth) = expected_closes.iter().rposition(|close| *close == token) else {
+                diagnostics.push(format!("removed unmatched `{token}` at token {position}"));
+                continue;
+            };
+            for close in expected_closes.drain(depth + 1..).rev() {
+                diagnostics.push(format!(
+                    "inserted missing `{close}` before token {position}"
+                ));
+                balanced.push(close);
+            }
+            expected_closes.pop();
+        }
+        balanced.push(token);
+    }
+    for close in expected_closes.into_iter().rev() {
+        diagnostics.push(format!(
+            "inserted missing `{close}` at the end of the input"
+        ));
+        balanced.push(close);
+    }
+    (balanced, diagnostics)
 }
 
 fn create_chunks<'src>(
     chunks: &mut Chunks<'src>,
     groups: Vec<Group<'src>>,
     mut attach_to: Vec<ChunkId>,
-) -> Vec<ChunkId> /* First */ {
+    role: Role,
+) -> Result<Vec<ChunkId>, Error> /* First */ {
     for group in groups.into_iter().rev() {
         match group {
             Group::Simple(tokens) => {
                 let id = chunks.allocate(Chunk {
                     tokens,
                     childs: attach_to,
-                });
+                    role,
+                })?;
                 attach_to = vec![id];
             }
             Group::Repetition { content, separator } => {
                 // With zero repetitions we don't need an extra node to be created.
 
                 // With one repetition we create chunks attached to the next set of chunks.
-                let case_one_ids = create_chunks(chunks, content.clone(), attach_to.clone());
-                attach_to.extend(case_one_ids.iter().copied());
+                let case_one_ids =
+                    create_chunks(chunks, content.clone(), attach_to.clone(), Role::Content)?;
+                extend_unique(&mut attach_to, &case_one_ids);
 
                 // With two repetitions we create chunks attached to the first repetition.
                 let attach_second_to = if let Some(sep) = separator {
@@ -86,24 +406,35 @@ fn create_chunks<'src>(
                     vec![chunks.allocate(Chunk {
                         tokens: vec![sep],
                         childs: case_one_ids,
-                    })]
+                        role: Role::Separator,
+                    })?]
                 } else {
                     case_one_ids
                 };
-                let case_two_ids = create_chunks(chunks, content, attach_second_to);
-                attach_to.extend(case_two_ids.into_iter());
+                let case_two_ids = create_chunks(chunks, content, attach_second_to, Role::Content)?;
+                extend_unique(&mut attach_to, &case_two_ids);
             }
         }
     }
-    attach_to
+    Ok(attach_to)
+}
+
+// Nested repetitions with zero repetitions can lead to the same chunk being reachable from
+// multiple cases: avoid adding duplicate edges in that case.
+fn extend_unique(ids: &mut Vec<ChunkId>, new: &[ChunkId]) {
+    for id in new {
+        if !ids.contains(id) {
+            ids.push(*id);
+        }
+    }
 }
 
 // Debug impls to make the tests look better:
 
 struct ListAsMap<'a, T>(&'a Vec<T>);
 
-impl<T: std::fmt::Debug> std::fmt::Debug for ListAsMap<'_, T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl<T: core::fmt::Debug> core::fmt::Debug for ListAsMap<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let mut map = f.debug_map();
         for (i, item) in self.0.iter().enumerate() {
             map.entry(&i, item);
@@ -114,29 +445,30 @@ impl<T: std::fmt::Debug> std::fmt::Debug for ListAsMap<'_, T> {
 
 struct ForceSingleLine<T>(T);
 
-impl<T: std::fmt::Debug> std::fmt::Debug for ForceSingleLine<T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl<T: core::fmt::Debug> core::fmt::Debug for ForceSingleLine<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{:?}", self.0)
     }
 }
 
-impl std::fmt::Debug for Chunk<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for Chunk<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("Chunk")
             .field("tokens", &self.tokens)
             .field("childs", &ForceSingleLine(&self.childs))
+            .field("role", &self.role)
             .finish()
     }
 }
 
-impl std::fmt::Debug for ChunkId {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for ChunkId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "#{}", self.0)
     }
 }
 
-impl std::fmt::Debug for Chunks<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for Chunks<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("Chunks")
             .field("inner", &ListAsMap(&self.inner))
             .field("firsts", &ForceSingleLine(&self.firsts))
@@ -169,6 +501,7 @@ mod tests {
                             Token( ] ),
                         ],
                         childs: [],
+                        role: Delimiter,
                     },
                 },
                 firsts: [#0],
@@ -191,30 +524,35 @@ mod tests {
                             Token( ] ),
                         ],
                         childs: [],
+                        role: Delimiter,
                     },
                     1: Chunk {
                         tokens: [
                             Token( 1 ),
                         ],
                         childs: [#0],
+                        role: Content,
                     },
                     2: Chunk {
                         tokens: [
                             Token( , ),
                         ],
                         childs: [#1],
+                        role: Separator,
                     },
                     3: Chunk {
                         tokens: [
                             Token( 1 ),
                         ],
                         childs: [#2],
+                        role: Content,
                     },
                     4: Chunk {
                         tokens: [
                             Token( [ ),
                         ],
                         childs: [#0, #1, #3],
+                        role: Delimiter,
                     },
                 },
                 firsts: [#4],
@@ -237,6 +575,7 @@ mod tests {
                             Token( ] ),
                         ],
                         childs: [],
+                        role: Delimiter,
                     },
                     1: Chunk {
                         tokens: [
@@ -244,6 +583,7 @@ mod tests {
                             Token( , ),
                         ],
                         childs: [#0],
+                        role: Content,
                     },
                     2: Chunk {
                         tokens: [
@@ -251,6 +591,7 @@ mod tests {
                             Token( , ),
                         ],
                         childs: [#1],
+                        role: Content,
                     },
                     3: Chunk {
                         tokens: [
@@ -258,12 +599,14 @@ mod tests {
                             Token( , ),
                         ],
                         childs: [#0, #1, #2],
+                        role: Content,
                     },
                     4: Chunk {
                         tokens: [
                             Token( , ),
                         ],
                         childs: [#3],
+                        role: Separator,
                     },
                     5: Chunk {
                         tokens: [
@@ -271,6 +614,7 @@ mod tests {
                             Token( , ),
                         ],
                         childs: [#4],
+                        role: Content,
                     },
                     6: Chunk {
                         tokens: [
@@ -278,6 +622,7 @@ mod tests {
                             Token( , ),
                         ],
                         childs: [#5],
+                        role: Content,
                     },
                     7: Chunk {
                         tokens: [
@@ -285,12 +630,346 @@ mod tests {
                             Token( , ),
                         ],
                         childs: [#4, #5, #6],
+                        role: Content,
                     },
                     8: Chunk {
                         tokens: [
                             Token( [ ),
                         ],
                         childs: [#0, #3, #7],
+                        role: Delimiter,
+                    },
+                },
+                firsts: [#8],
+            },
+        )
+        "###);
+    }
+
+    #[test]
+    fn test_chunk_accessors() {
+        let chunks = of("[$(1),*]").unwrap();
+
+        let first = chunks.firsts().next().unwrap();
+        assert_eq!(&[Token::OpenSquare], first.tokens());
+        assert_eq!(3, first.children().len());
+        assert_eq!(Role::Delimiter, first.role());
+
+        let last = chunks.get(first.children()[0]);
+        assert_eq!(&[Token::CloseSquare], last.tokens());
+        assert!(last.children().is_empty());
+    }
+
+    #[test]
+    fn test_map_tokens() {
+        let mut chunks = of("[$(1, 2),*]").unwrap();
+        chunks.map_tokens(|token| {
+            if let Token::Number(n) = token {
+                *n += 1;
+            }
+        });
+        assert_eq!(of("[$(2, 3),*]").unwrap(), chunks);
+    }
+
+    #[test]
+    fn test_tokens_at_depth() {
+        let chunks = of("[$(1),*]").unwrap();
+        assert_eq!(
+            vec![
+                BTreeSet::from([Token::OpenSquare]),
+                BTreeSet::from([Token::CloseSquare, Token::Number(1)]),
+                BTreeSet::from([Token::Comma]),
+            ],
+            chunks.tokens_at_depth()
+        );
+    }
+
+    #[test]
+    fn test_edges() {
+        let chunks = of("[$(1),*]").unwrap();
+        let edges = chunks.edges().collect::<BTreeSet<_>>();
+        let expected = [(1, 0), (2, 1), (3, 2), (4, 0), (4, 1), (4, 3)]
+            .into_iter()
+            .map(|(from, to)| (ChunkId(from), ChunkId(to)))
+            .collect::<BTreeSet<_>>();
+        assert_eq!(expected, edges);
+    }
+
+    #[test]
+    fn test_walk_with() {
+        let walk = |input| {
+            let chunks = of(input).unwrap();
+            let mut events = Vec::new();
+            chunks.walk_with(|event| {
+                events.push(match event {
+                    WalkEvent::Enter(id) => format!("enter {id:?} {:?}", chunks.get(id).tokens()),
+                    WalkEvent::Leave(id) => format!("leave {id:?}"),
+                })
+            });
+            events
+        };
+
+        assert_eq!(
+            vec!["enter #0 [Token( [ ), Token( 1 ), Token( ] )]", "leave #0"],
+            walk("[1]")
+        );
+        assert_eq!(
+            vec![
+                "enter #3 [Token( [ )]",
+                "enter #0 [Token( ] )]",
+                "leave #0",
+                "enter #1 [Token( 1 )]",
+                "enter #0 [Token( ] )]",
+                "leave #0",
+                "leave #1",
+                "enter #2 [Token( 1 )]",
+                "enter #1 [Token( 1 )]",
+                "enter #0 [Token( ] )]",
+                "leave #0",
+                "leave #1",
+                "leave #2",
+                "leave #3",
+            ],
+            walk("[$(1)*]")
+        );
+    }
+
+    #[test]
+    fn test_accepting() {
+        let mut chunks = of("[$(1),*]").unwrap();
+        let accepting = |chunks: &Chunks<'_>| {
+            (0..chunks.inner.len())
+                .map(ChunkId)
+                .filter(|id| chunks.is_accepting(*id))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec![ChunkId(0)], accepting(&chunks));
+
+        chunks.set_accepting(ChunkId(1), true);
+        chunks.set_accepting(ChunkId(0), false);
+        assert_eq!(vec![ChunkId(1)], accepting(&chunks));
+    }
+
+    #[test]
+    fn test_sample_shortest() {
+        use crate::lexer::render;
+
+        let sample = |input: &str| of(input).unwrap().sample_shortest().map(|t| render(&t));
+        assert_eq!(Some("[]".into()), sample("[$(1),*]"));
+        assert_eq!(Some("c".into()), sample("$(a)* $(a b)* c"));
+        assert_eq!(Some("f()".into()), sample("f($(x),* $(y)*)"));
+        assert_eq!(None, sample(""));
+    }
+
+    #[test]
+    fn test_approximate_size_hint() {
+        assert_eq!(
+            SizeHint {
+                chunks: 5,
+                edges: 6,
+                expansions: 3,
+            },
+            of("[$(1),*]").unwrap().approximate_size_hint()
+        );
+
+        // Each repetition doubles the expansions, which saturate instead of overflowing.
+        let hint = of(&"$(1)* ".repeat(100)).unwrap().approximate_size_hint();
+        assert_eq!(u64::MAX, hint.expansions);
+    }
+
+    #[test]
+    fn test_bfs_levels() {
+        let chunks = of("[$(1, $(3,)*),*]").unwrap();
+        assert_debug_snapshot!(chunks.bfs_levels(), @r###"
+        [
+            [
+                #8,
+            ],
+            [
+                #0,
+                #3,
+                #7,
+            ],
+            [
+                #1,
+                #2,
+                #4,
+                #5,
+                #6,
+            ],
+        ]
+        "###);
+    }
+
+    #[test]
+    fn test_collect_separators() {
+        let separators = |input| of(input).unwrap().collect_separators();
+        assert_eq!(BTreeSet::from([Token::Comma]), separators("[$(1),*]"));
+        assert_eq!(
+            BTreeSet::from([Token::Comma, Token::Semicolon]),
+            separators("$($(a),*);* $(b),* $(c)*")
+        );
+        // Commas outside of repetitions are not separators.
+        assert!(separators("(1, 2)").is_empty());
+    }
+
+    #[test]
+    fn test_is_empty_language() {
+        assert!(!of("[$(1),*]").unwrap().is_empty_language());
+        assert!(!of("$(a)*").unwrap().is_empty_language());
+        assert!(of("").unwrap().is_empty_language());
+
+        // The only leaf is `]`, so without it the repetition loops forever.
+        let mut chunks = of("[$(1),*]").unwrap();
+        chunks.set_accepting(ChunkId(0), false);
+        assert!(chunks.is_empty_language());
+        assert_eq!(None, chunks.sample_shortest());
+
+        // Any reachable accepting chunk is enough, even if it's not a leaf.
+        chunks.set_accepting(ChunkId(1), true);
+        assert!(!chunks.is_empty_language());
+    }
+
+    #[test]
+    fn test_of_repaired() {
+        let (chunks, diagnostics) = of_repaired("[1").unwrap();
+        assert_eq!(of("[1]").unwrap(), chunks);
+        assert_eq!(
+            vec!["inserted missing `]` at the end of the input"],
+            diagnostics
+        );
+
+        let (chunks, diagnostics) = of_repaired("[$(1),*)] )").unwrap();
+        assert_eq!(of("[$(1),*]").unwrap(), chunks);
+        assert_eq!(
+            vec![
+                "removed unmatched `)` at token 7",
+                "removed unmatched `)` at token 9",
+            ],
+            diagnostics
+        );
+
+        let (chunks, diagnostics) = of_repaired("{[(1}").unwrap();
+        assert_eq!(of("{[(1)]}").unwrap(), chunks);
+        assert_eq!(
+            vec![
+                "inserted missing `)` before token 4",
+                "inserted missing `]` before token 4",
+            ],
+            diagnostics
+        );
+
+        // Inputs without unbalanced delimiters are expanded as-is.
+        let (chunks, diagnostics) = of_repaired("[$(1),*]").unwrap();
+        assert_eq!(of("[$(1),*]").unwrap(), chunks);
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_from_groups() {
+        let groups = vec![
+            Group::Simple(vec![Token::OpenSquare]),
+            Group::Repetition {
+                content: vec![Group::Simple(vec![Token::Number(1)])],
+                separator: Some(Token::Comma),
+            },
+            Group::Simple(vec![Token::CloseSquare]),
+        ];
+
+        assert_eq!(
+            of("[$(1),*]").unwrap(),
+            Chunks::from_groups(groups).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_from_groups_with_limit() {
+        let groups = || {
+            vec![
+                Group::Simple(vec![Token::OpenSquare]),
+                Group::Repetition {
+                    content: vec![Group::Simple(vec![Token::Number(1)])],
+                    separator: None,
+                },
+            ]
+        };
+
+        assert!(Chunks::from_groups_with_limit(groups(), 3).is_ok());
+        let err = Chunks::from_groups_with_limit(groups(), 2).unwrap_err();
+        assert_eq!("expansion requires more than 2 chunks", err.to_string());
+    }
+
+    #[test]
+    fn test_expansion_nested_separators() {
+        let input = "[$($(1);*),*]";
+        let result = of(input);
+
+        assert_debug_snapshot!(result, @r###"
+        Ok(
+            Chunks {
+                inner: {
+                    0: Chunk {
+                        tokens: [
+                            Token( ] ),
+                        ],
+                        childs: [],
+                        role: Delimiter,
+                    },
+                    1: Chunk {
+                        tokens: [
+                            Token( 1 ),
+                        ],
+                        childs: [#0],
+                        role: Content,
+                    },
+                    2: Chunk {
+                        tokens: [
+                            Token( ; ),
+                        ],
+                        childs: [#1],
+                        role: Separator,
+                    },
+                    3: Chunk {
+                        tokens: [
+                            Token( 1 ),
+                        ],
+                        childs: [#2],
+                        role: Content,
+                    },
+                    4: Chunk {
+                        tokens: [
+                            Token( , ),
+                        ],
+                        childs: [#0, #1, #3],
+                        role: Separator,
+                    },
+                    5: Chunk {
+                        tokens: [
+                            Token( 1 ),
+                        ],
+                        childs: [#4],
+                        role: Content,
+                    },
+                    6: Chunk {
+                        tokens: [
+                            Token( ; ),
+                        ],
+                        childs: [#5],
+                        role: Separator,
+                    },
+                    7: Chunk {
+                        tokens: [
+                            Token( 1 ),
+                        ],
+                        childs: [#6],
+                        role: Content,
+                    },
+                    8: Chunk {
+                        tokens: [
+                            Token( [ ),
+                        ],
+                        childs: [#0, #1, #3, #4, #5, #7],
+                        role: Delimiter,
                     },
                 },
                 firsts: [#8],
@@ -0,0 +1,89 @@
+use crate::expansion::{of, Chunks};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use anyhow::Error;
+
+/// [`Chunks`] borrows from the source it was created from, which forces callers to keep the
+/// source around for as long as the chunks are used. [`OwnedChunks`] instead owns the source
+/// string (or the encoded graph), and rebuilds the chunks from it whenever they are needed.
+pub(crate) struct OwnedChunks {
+    stored: Stored,
+}
+
+enum Stored {
+    Source(Box<str>),
+    Encoded(Box<[u8]>),
+}
+
+impl OwnedChunks {
+    /// Take ownership of the source, returning an error if it can't be expanded.
+    pub(crate) fn parse(source: String) -> Result<Self, Error> {
+        of(&source)?;
+        Ok(Self {
+            stored: Stored::Source(source.into_boxed_str()),
+        })
+    }
+
+    /// Take ownership of a graph encoded with [`Chunks::encode`], returning an error if it can't
+    /// be decoded.
+    pub(crate) fn decode(encoded: Vec<u8>) -> Result<Self, Error> {
+        Chunks::decode(&encoded)?;
+        Ok(Self {
+            stored: Stored::Encoded(encoded.into_boxed_slice()),
+        })
+    }
+
+    /// Rebuild the chunks, borrowing from the owned source or encoded graph. This expands or
+    /// decodes again on every call, so callers needing the chunks multiple times should keep the
+    /// returned value around.
+    pub(crate) fn chunks(&self) -> Chunks<'_> {
+        match &self.stored {
+            Stored::Source(source) => {
+                of(source).expect("the source was expanded successfully when parsed")
+            }
+            Stored::Encoded(encoded) => {
+                Chunks::decode(encoded).expect("the graph was decoded successfully before")
+            }
+        }
+    }
+
+    /// Return the source the chunks are expanded from, or `None` if they were decoded.
+    pub(crate) fn source(&self) -> Option<&str> {
+        match &self.stored {
+            Stored::Source(source) => Some(source),
+            Stored::Encoded(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_owned() {
+        let source = String::from("[$(1),*]");
+        let owned = OwnedChunks::parse(source.clone()).unwrap();
+        drop(source);
+
+        assert_eq!(Some("[$(1),*]"), owned.source());
+        assert_eq!(of("[$(1),*]").unwrap(), owned.chunks());
+    }
+
+    #[test]
+    fn test_parse_owned_invalid() {
+        let err = OwnedChunks::parse(String::from("$(1")).err().unwrap();
+        assert_eq!("Unbalanced delimiters", err.to_string());
+    }
+
+    #[test]
+    fn test_decode_owned() {
+        let encoded = of(r#"[$("a"),*]"#).unwrap().encode();
+        let owned = OwnedChunks::decode(encoded).unwrap();
+
+        assert_eq!(None, owned.source());
+        assert_eq!(of(r#"[$("a"),*]"#).unwrap(), owned.chunks());
+        assert!(OwnedChunks::decode(b"JSON{}".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use crate::expansion::{ChunkId, Chunks};
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+impl Chunks<'_> {
+    /// Render the graph as a compact pattern, reconstructing the repetitions from the shape of the
+    /// graph. For example, the graph created from `[$(1),*]` is rendered as `[ (1),* ]`.
+    ///
+    /// This is meant for documentation and to check the graph matches the intended matcher. The
+    /// reconstruction is approximate: shapes that don't look like an unrolled repetition are
+    /// rendered as alternatives, like `(a | b)`.
+    pub(crate) fn to_pattern(&self) -> String {
+        let mut pieces = Vec::new();
+        self.render_sequence(self.firsts.clone(), None, &mut pieces);
+        pieces.join(" ")
+    }
+
+    /// Render all the paths starting from `starts`, stopping when reaching `until` (or when
+    /// there are no more chunks if `until` is `None`).
+    fn render_sequence(
+        &self,
+        mut starts: Vec<ChunkId>,
+        until: Option<ChunkId>,
+        pieces: &mut Vec<String>,
+    ) {
+        loop {
+            match starts.as_slice() {
+                [] => return,
+                [single] if Some(*single) == until => return,
+                [single] => {
+                    let chunk = self.get(*single);
+                    pieces.extend(chunk.tokens.iter().map(|t| t.to_string()));
+                    starts = chunk.childs.clone();
+                }
+                _ => {
+                    // All the branches meet again at the join, so render what's between them as
+                    // alternatives and then continue from the join.
+                    let join = self.join(&starts);
+                    let alternatives = starts
+                        .iter()
+                        .map(|start| {
+                            let mut branch = Vec::new();
+                            self.render_sequence(vec![*start], join, &mut branch);
+                            branch.join(" ")
+                        })
+                        .collect::<Vec<_>>();
+                    pieces.push(summarize(alternatives));
+
+                    match join {
+                        Some(join) if Some(join) != until => starts = vec![join],
+                        _ => return,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Find the closest chunk reachable from all the provided chunks, or `None` if the paths only
+    /// meet at the end of the graph.
+    fn join(&self, starts: &[ChunkId]) -> Option<ChunkId> {
+        let mut common: Option<BTreeSet<ChunkId>> = None;
+        for start in starts {
+            let reachable = self.reachable(*start);
+            common = Some(match common {
+                None => reachable,
+                Some(common) => common.intersection(&reachable).copied().collect(),
+            });
+        }
+        // Children are always allocated before their parents, so the closest chunk is the one
+        // with the highest ID.
+        common?.last().copied()
+    }
+
+    /// Return all chunks reachable from `start`, including itself.
+    pub(super) fn reachable(&self, start: ChunkId) -> BTreeSet<ChunkId> {
+        let mut visited = BTreeSet::new();
+        let mut queue = vec![start];
+        while let Some(id) = queue.pop() {
+            if visited.insert(id) {
+                queue.extend(self.get(id).childs.iter().copied());
+            }
+        }
+        visited
+    }
+}
+
+/// Render the alternative branches, using repetition operators if they look like an unrolled
+/// repetition (`x` and `x sep x`, or `x` and `x x`).
+fn summarize(alternatives: Vec<String>) -> String {
+    let optional = alternatives.iter().any(|a| a.is_empty());
+    let mut alternatives = alternatives
+        .into_iter()
+        .filter(|a| !a.is_empty())
+        .collect::<Vec<_>>();
+    alternatives.sort_by_key(|a| a.len());
+    alternatives.dedup();
+
+    let (min, max) = if optional { ("*", "?") } else { ("+", "") };
+    match alternatives.as_slice() {
+        [one] => format!("({one}){max}"),
+        [one, two] => match repetition_separator(one, two) {
+            Some(separator) => format!("({one}){separator}{min}"),
+            None => format!("({one} | {two}){max}"),
+        },
+        many => format!("({}){max}", many.join(" | ")),
+    }
+}
+
+/// If `two` is `one` repeated twice, return the separator between the repetitions.
+fn repetition_separator<'a>(one: &str, two: &'a str) -> Option<&'a str> {
+    let middle = two.strip_prefix(one)?.strip_suffix(one)?;
+    match middle.strip_prefix(' ')?.strip_suffix(' ') {
+        Some(separator) if !separator.contains(' ') => Some(separator),
+        Some(_) => None,
+        None if middle == " " => Some(""),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expansion::of;
+
+    #[test]
+    fn test_to_pattern() {
+        assert_eq!("[ 1 , 2 ]", of("[1, 2]").unwrap().to_pattern());
+        assert_eq!("[ (1),* ]", of("[$(1),*]").unwrap().to_pattern());
+        assert_eq!("[ (1 2)* ]", of("[$(1 2)*]").unwrap().to_pattern());
+        // Without tokens after the repetition the last instance is where all paths meet, so the
+        // repetition can't be recognized.
+        assert_eq!("(x ;)? x", of("$(x);*").unwrap().to_pattern());
+    }
+}
@@ -1,4 +1,5 @@
 use crate::lexer::Token;
+use alloc::vec::Vec;
 use anyhow::{anyhow, bail, ensure, Error};
 
 pub(super) fn parse_tokenstream(tokens: Vec<Token>) -> Result<Vec<TokenTree>, Error> {
@@ -28,23 +29,37 @@ fn parse_tokentree<'a, 'src>(
     // Eat the `$`.
     let input = &input[1..];
 
-    // Eat the `(`.
-    ensure!(
-        matches!(input.first(), Some(Token::OpenParen)),
-        "Expected `(` after the `$`"
-    );
+    // `$$` escapes a literal `$`, which doesn't start a repetition or a metavariable.
+    if let Some(Token::Dollar) = input.first() {
+        return Ok((TokenTree::Token(Token::Dollar), &input[1..]));
+    }
+
+    // A metavariable like `$name` or `$name:kind`.
+    if let Some(Token::Ident(name)) = input.first() {
+        let (kind, tail) = match input.get(1..3) {
+            Some([Token::Colon, Token::Ident(kind)]) => (Some(*kind), &input[3..]),
+            _ => (None, &input[1..]),
+        };
+        return Ok((TokenTree::Metavariable { name, kind }, tail));
+    }
+
+    // Eat the `(`, `[` or `{`.
+    let open = input.first().copied();
+    let Some(close) = open.and_then(|open| open.matching_close()) else {
+        bail!("Expected `(`, `[` or `{{` after the `$`");
+    };
     let input = &input[1..];
 
-    // Depth = 0 => we reached the closing paren!
+    // Depth = 0 => we reached the closing delimiter!
     let mut depth = 1;
     let mut idx = 0;
     while depth > 0 {
         match input.get(idx) {
-            Some(Token::CloseParen) => depth -= 1,
-            Some(Token::OpenParen) => depth += 1,
+            Some(tok) if *tok == close => depth -= 1,
+            Some(tok) if Some(*tok) == open => depth += 1,
             Some(_) => {}
 
-            None => bail!("Unbalanced parentheses"),
+            None => bail!("Unbalanced delimiters"),
         }
 
         idx += 1;
@@ -52,7 +67,7 @@ fn parse_tokentree<'a, 'src>(
 
     let (inner_tokens, tail) = input.split_at(idx);
 
-    // Remove `)`.
+    // Remove the closing delimiter.
     let mut inner_tokens = &inner_tokens[..inner_tokens.len() - 1];
     // Remove repetition seperator and operator.
     //
@@ -87,6 +102,10 @@ fn parse_tokentree<'a, 'src>(
 pub(super) enum TokenTree<'src> {
     Token(Token<'src>),
     Repetition(TokenRepetition<'src>),
+    Metavariable {
+        name: &'src str,
+        kind: Option<&'src str>,
+    },
 }
 
 #[derive(Debug)]
@@ -104,7 +123,7 @@ mod tests {
     #[test]
     fn test_parse_tokenstream() {
         let input = "[$(1, 2),*]";
-        let lexed = Lexer::new(input).collect::<Vec<_>>();
+        let lexed = Lexer::tokenize_all(input).unwrap();
         let stream = parse_tokenstream(lexed).unwrap();
 
         assert_debug_snapshot!(stream, @r###"
@@ -136,4 +155,107 @@ mod tests {
         ]
         "###);
     }
+
+    #[test]
+    fn test_parse_tokenstream_metavariables() {
+        let input = "$x $y:expr";
+        let lexed = Lexer::tokenize_all(input).unwrap();
+        let stream = parse_tokenstream(lexed).unwrap();
+
+        assert_debug_snapshot!(stream, @r###"
+        [
+            Metavariable {
+                name: "x",
+                kind: None,
+            },
+            Metavariable {
+                name: "y",
+                kind: Some(
+                    "expr",
+                ),
+            },
+        ]
+        "###);
+    }
+
+    #[test]
+    fn test_parse_tokenstream_square_delimiter() {
+        let input = "$[1]*";
+        let lexed = Lexer::tokenize_all(input).unwrap();
+        let stream = parse_tokenstream(lexed).unwrap();
+
+        assert_debug_snapshot!(stream, @r###"
+        [
+            Repetition(
+                TokenRepetition {
+                    repeated: [
+                        Token(
+                            Token( 1 ),
+                        ),
+                    ],
+                    separator: None,
+                },
+            ),
+        ]
+        "###);
+    }
+
+    #[test]
+    fn test_parse_tokenstream_brace_delimiter() {
+        let input = "${1}*";
+        let lexed = Lexer::tokenize_all(input).unwrap();
+        let stream = parse_tokenstream(lexed).unwrap();
+
+        assert_debug_snapshot!(stream, @r###"
+        [
+            Repetition(
+                TokenRepetition {
+                    repeated: [
+                        Token(
+                            Token( 1 ),
+                        ),
+                    ],
+                    separator: None,
+                },
+            ),
+        ]
+        "###);
+    }
+
+    #[test]
+    fn test_parse_tokenstream_escaped_dollar() {
+        let input = "$$(1) $$$(2)*";
+        let lexed = Lexer::tokenize_all(input).unwrap();
+        let stream = parse_tokenstream(lexed).unwrap();
+
+        assert_debug_snapshot!(stream, @r###"
+        [
+            Token(
+                Token( $ ),
+            ),
+            Token(
+                Token( ( ),
+            ),
+            Token(
+                Token( 1 ),
+            ),
+            Token(
+                Token( ) ),
+            ),
+            Token(
+                Token( $ ),
+            ),
+            Repetition(
+                TokenRepetition {
+                    repeated: [
+                        Token(
+                            Token( 2 ),
+                        ),
+                    ],
+                    separator: None,
+                },
+            ),
+        ]
+        "###);
+    }
 }
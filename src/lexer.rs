@@ -1,103 +1,679 @@
-#[derive(Clone, Copy, PartialEq)]
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use anyhow::{anyhow, bail, ensure, Error};
+use core::fmt::Write;
+
+/// Tokens are ordered first by their kind (in the order the variants are declared), and then by
+/// their content: numbers by their (signed) value, and strings lexicographically.
+///
+/// Every token is equal to itself, which `expect` and the deduplication of chunks rely on. This
+/// holds as all contents are integers or strings: contents without a total order (like floats,
+/// where NaN is not equal to itself) would need to be compared by their bit pattern instead.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) enum Token<'a> {
     OpenParen,
     CloseParen,
     OpenSquare,
     CloseSquare,
+    OpenBrace,
+    CloseBrace,
     Comma,
     Plus,
     Dash,
     Semicolon,
     Dollar,
     Star,
+    StarStar,
     Slash,
+    Colon,
+    Dot,
+    DotDot,
+    Question,
+    Bang,
+    Ampersand,
+    Pipe,
+    Equals,
+    Less,
+    Greater,
+    Percent,
+    Caret,
+    Tilde,
+    At,
+    Hash,
+    /// Only produced if the lexer was configured with
+    /// [`significant_newlines`](Lexer::significant_newlines).
+    Newline,
     Number(i64),
+    /// A number along with how it was written in the source (for example with leading zeros).
+    /// Only produced if the lexer was configured with
+    /// [`preserve_number_text`](Lexer::preserve_number_text).
+    NumberText {
+        value: i64,
+        text: &'a str,
+    },
     String(&'a str),
+    /// A byte string like `b"\xff"`, containing the bytes between the quotes as written in the
+    /// source (with the escapes not yet processed, see [`Token::byte_string_value`]).
+    ByteString(&'a [u8]),
+    Char(char),
+    Ident(&'a str),
+    /// An identifier that is one of the keywords the lexer was configured with, see
+    /// [`Lexer::keywords`].
+    Keyword(&'a str),
+    /// A character that is not part of any token.
+    Unknown(char),
+}
+
+/// The kind of a [`Token`], without its contents. This is useful to compare or group tokens when
+/// their contents don't matter, without borrowing them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum TokenKind {
+    OpenParen,
+    CloseParen,
+    OpenSquare,
+    CloseSquare,
+    OpenBrace,
+    CloseBrace,
+    Comma,
+    Plus,
+    Dash,
+    Semicolon,
+    Dollar,
+    Star,
+    StarStar,
+    Slash,
+    Colon,
+    Dot,
+    DotDot,
+    Question,
+    Bang,
+    Ampersand,
+    Pipe,
+    Equals,
+    Less,
+    Greater,
+    Percent,
+    Caret,
+    Tilde,
+    At,
+    Hash,
+    Newline,
+    Number,
+    String,
+    ByteString,
+    Char,
+    Ident,
+    Keyword,
+    Unknown,
 }
 
-impl std::fmt::Debug for Token<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Token( ")?;
+impl core::fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl Token<'_> {
+    /// Return the kind of the token, discarding its contents.
+    pub(crate) fn kind(&self) -> TokenKind {
         match self {
-            Self::OpenParen => write!(f, "(")?,
-            Self::CloseParen => write!(f, ")")?,
-            Self::OpenSquare => write!(f, "[")?,
-            Self::CloseSquare => write!(f, "]")?,
-            Self::Comma => write!(f, ",")?,
-            Self::Plus => write!(f, "+")?,
-            Self::Dash => write!(f, "-")?,
-            Self::Semicolon => write!(f, ";")?,
-            Self::Dollar => write!(f, "$")?,
-            Self::Star => write!(f, "*")?,
-            Self::Slash => write!(f, "/")?,
-            Self::Number(arg0) => write!(f, "{arg0}")?,
-            Self::String(arg0) => write!(f, "{arg0:?}")?,
+            Token::OpenParen => TokenKind::OpenParen,
+            Token::CloseParen => TokenKind::CloseParen,
+            Token::OpenSquare => TokenKind::OpenSquare,
+            Token::CloseSquare => TokenKind::CloseSquare,
+            Token::OpenBrace => TokenKind::OpenBrace,
+            Token::CloseBrace => TokenKind::CloseBrace,
+            Token::Comma => TokenKind::Comma,
+            Token::Plus => TokenKind::Plus,
+            Token::Dash => TokenKind::Dash,
+            Token::Semicolon => TokenKind::Semicolon,
+            Token::Dollar => TokenKind::Dollar,
+            Token::Star => TokenKind::Star,
+            Token::StarStar => TokenKind::StarStar,
+            Token::Slash => TokenKind::Slash,
+            Token::Colon => TokenKind::Colon,
+            Token::Dot => TokenKind::Dot,
+            Token::DotDot => TokenKind::DotDot,
+            Token::Question => TokenKind::Question,
+            Token::Bang => TokenKind::Bang,
+            Token::Ampersand => TokenKind::Ampersand,
+            Token::Pipe => TokenKind::Pipe,
+            Token::Equals => TokenKind::Equals,
+            Token::Less => TokenKind::Less,
+            Token::Greater => TokenKind::Greater,
+            Token::Percent => TokenKind::Percent,
+            Token::Caret => TokenKind::Caret,
+            Token::Tilde => TokenKind::Tilde,
+            Token::At => TokenKind::At,
+            Token::Hash => TokenKind::Hash,
+            Token::Newline => TokenKind::Newline,
+            Token::Number(_) | Token::NumberText { .. } => TokenKind::Number,
+            Token::String(_) => TokenKind::String,
+            Token::ByteString(_) => TokenKind::ByteString,
+            Token::Char(_) => TokenKind::Char,
+            Token::Ident(_) => TokenKind::Ident,
+            Token::Keyword(_) => TokenKind::Keyword,
+            Token::Unknown(_) => TokenKind::Unknown,
         }
-        write!(f, " )")
+    }
+
+    /// If the token is an opening delimiter, return the token closing it.
+    pub(crate) fn matching_close(&self) -> Option<Token<'static>> {
+        match self {
+            Token::OpenParen => Some(Token::CloseParen),
+            Token::OpenSquare => Some(Token::CloseSquare),
+            Token::OpenBrace => Some(Token::CloseBrace),
+            _ => None,
+        }
+    }
+
+    /// Whether the token closes a delimiter opened by a token with a
+    /// [`matching_close`](Token::matching_close).
+    pub(crate) fn is_close_delimiter(&self) -> bool {
+        matches!(
+            self,
+            Token::CloseParen | Token::CloseSquare | Token::CloseBrace
+        )
+    }
+
+    /// Return how many bytes the token occupied in the source code, ignoring whitespace around it.
+    ///
+    /// This is approximate for strings, as escapes are not tracked.
+    pub(crate) fn len_in_source(&self) -> usize {
+        match self {
+            Token::Number(number) => number.to_string().len(),
+            Token::NumberText { text, .. } => text.len(),
+            Token::String(string) => string.len() + 2,
+            Token::ByteString(bytes) => bytes.len() + 3,
+            Token::Char('\n' | '\r' | '\t' | '\0' | '\\' | '\'' | '"') => 4,
+            Token::Char(c) => c.len_utf8() + 2,
+            Token::Ident(ident) | Token::Keyword(ident) => ident.len(),
+            Token::Unknown(c) => c.len_utf8(),
+            Token::StarStar | Token::DotDot => 2,
+            _ => 1,
+        }
+    }
+
+    /// Return the value of the token if it's a number.
+    pub(crate) fn as_number(&self) -> Option<i64> {
+        match self {
+            Token::Number(number) | Token::NumberText { value: number, .. } => Some(*number),
+            _ => None,
+        }
+    }
+
+    /// Return the bytes of the token if it's a byte string, with the escapes processed.
+    pub(crate) fn byte_string_value(&self) -> Option<Vec<u8>> {
+        match self {
+            Token::ByteString(raw) => {
+                Some(unescape_byte_string(raw).expect("byte string was validated by the lexer"))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the token is an identifier (or a keyword, if the lexer was configured with them)
+    /// with the provided name. String literals with the same contents never match.
+    pub(crate) fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self, Token::Ident(ident) | Token::Keyword(ident) if *ident == keyword)
+    }
+
+    /// Whether the token carries no meaning for the parser, and can be skipped without changing
+    /// how the program is parsed. [`Token::Newline`] is the only such token.
+    pub(crate) fn is_trivia(&self) -> bool {
+        matches!(self, Token::Newline)
+    }
+
+    /// Whether the token can be used as an operator between two expressions.
+    pub(crate) fn is_binary_op(&self) -> bool {
+        matches!(self, Token::Plus | Token::Dash)
+    }
+
+    /// Whether the token can be used as an operator before an expression.
+    pub(crate) fn is_unary_op(&self) -> bool {
+        matches!(self, Token::Dash)
+    }
+}
+
+/// Render the tokens as source code that lexes back to the same tokens, only adding spaces
+/// between tokens that would otherwise merge (like `1 2` or `* *`).
+///
+/// Negative numbers are rendered with a leading `-`, which is lexed back as a separate token. Use
+/// [`render_with`] and [`NegativeNumbers::Parenthesized`] to keep their value when parsed as an
+/// expression instead.
+pub(crate) fn render(tokens: &[Token<'_>]) -> String {
+    render_with(tokens, NegativeNumbers::Bare)
+}
+
+/// Same as [`render`], choosing how negative numbers are rendered.
+pub(crate) fn render_with(tokens: &[Token<'_>], negatives: NegativeNumbers) -> String {
+    let mut output = String::new();
+    let mut previous: Option<String> = None;
+    for token in tokens {
+        let text = match (token, negatives) {
+            (Token::Number(number), NegativeNumbers::Parenthesized) if *number < 0 => {
+                format!("({number})")
+            }
+            _ => token.to_string(),
+        };
+        if let Some(previous) = &previous {
+            let joined = format!("{previous}{text}");
+            let separate = Lexer::new(previous).chain(Lexer::new(&text));
+            if !Lexer::new(&joined).eq(separate) {
+                output.push(' ');
+            }
+        }
+        output.push_str(&text);
+        previous = Some(text);
+    }
+    output
+}
+
+/// How [`render_with`] renders negative numbers. The lexer never produces them, as `-5` is lexed
+/// as [`Token::Dash`] followed by `5`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NegativeNumbers {
+    /// Render them as `-5`, which only keeps their value where a unary minus would apply.
+    Bare,
+    /// Render them as `(-5)`, which keeps their value in any expression.
+    Parenthesized,
+}
+
+/// Return the char represented by the escape `\` followed by `escaped` in a char literal, or
+/// `None` if the escape is not supported.
+pub(crate) fn unescape_char(escaped: char) -> Option<char> {
+    match escaped {
+        'n' => Some('\n'),
+        'r' => Some('\r'),
+        't' => Some('\t'),
+        '0' => Some('\0'),
+        '\\' => Some('\\'),
+        '\'' => Some('\''),
+        '"' => Some('"'),
+        _ => None,
+    }
+}
+
+/// Process the escapes in the contents of a byte string. Besides the escapes supported by char
+/// literals, arbitrary bytes can be written as `\xNN`.
+pub(crate) fn unescape_byte_string(raw: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut result = Vec::with_capacity(raw.len());
+    let mut bytes = raw.iter();
+    while let Some(byte) = bytes.next() {
+        if *byte != b'\\' {
+            result.push(*byte);
+            continue;
+        }
+        let escaped = match bytes.next() {
+            Some(b'n') => b'\n',
+            Some(b'r') => b'\r',
+            Some(b't') => b'\t',
+            Some(b'0') => b'\0',
+            Some(b'\\') => b'\\',
+            Some(b'\'') => b'\'',
+            Some(b'"') => b'"',
+            Some(b'x') => {
+                let digits = [bytes.next(), bytes.next()];
+                let hex = digits
+                    .iter()
+                    .map(|d| d.map(|d| (*d as char).to_digit(16)))
+                    .collect::<Option<Option<Vec<_>>>>()
+                    .flatten();
+                match hex.as_deref() {
+                    Some([high, low]) => (high * 16 + low) as u8,
+                    _ => bail!("invalid \\x escape in byte string"),
+                }
+            }
+            Some(other) => bail!("unknown escape in byte string: \\{}", *other as char),
+            None => bail!("unterminated escape in byte string"),
+        };
+        result.push(escaped);
+    }
+    Ok(result)
+}
+
+/// Compute a hash of the tokens that only depends on their contents, and is thus the same across
+/// runs, builds and platforms. This can be used as a key to cache the results of parsing.
+pub(crate) fn stable_hash(tokens: &[Token<'_>]) -> u64 {
+    let mut hasher = Fnv1a(0xcbf29ce484222325);
+    for token in tokens {
+        // Writing to the hasher can't fail.
+        let _ = write!(hasher, "{token}");
+        // 0xff never appears in UTF-8, so it can't be confused with the contents of a token.
+        hasher.write_byte(0xff);
+    }
+    hasher.0
+}
+
+/// The 64-bit FNV-1a hash function, which is simple and fully deterministic.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn write_byte(&mut self, byte: u8) {
+        self.0 ^= u64::from(byte);
+        self.0 = self.0.wrapping_mul(0x100000001b3);
     }
 }
 
+impl core::fmt::Write for Fnv1a {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        s.bytes().for_each(|byte| self.write_byte(byte));
+        Ok(())
+    }
+}
+
+impl core::fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::OpenParen => write!(f, "("),
+            Self::CloseParen => write!(f, ")"),
+            Self::OpenSquare => write!(f, "["),
+            Self::CloseSquare => write!(f, "]"),
+            Self::OpenBrace => write!(f, "{{"),
+            Self::CloseBrace => write!(f, "}}"),
+            Self::Comma => write!(f, ","),
+            Self::Plus => write!(f, "+"),
+            Self::Dash => write!(f, "-"),
+            Self::Semicolon => write!(f, ";"),
+            Self::Dollar => write!(f, "$"),
+            Self::Star => write!(f, "*"),
+            Self::StarStar => write!(f, "**"),
+            Self::Slash => write!(f, "/"),
+            Self::Colon => write!(f, ":"),
+            Self::Dot => write!(f, "."),
+            Self::DotDot => write!(f, ".."),
+            Self::Question => write!(f, "?"),
+            Self::Bang => write!(f, "!"),
+            Self::Ampersand => write!(f, "&"),
+            Self::Pipe => write!(f, "|"),
+            Self::Equals => write!(f, "="),
+            Self::Less => write!(f, "<"),
+            Self::Greater => write!(f, ">"),
+            Self::Percent => write!(f, "%"),
+            Self::Caret => write!(f, "^"),
+            Self::Tilde => write!(f, "~"),
+            Self::At => write!(f, "@"),
+            Self::Hash => write!(f, "#"),
+            Self::Newline => writeln!(f),
+            Self::Number(arg0) => write!(f, "{arg0}"),
+            Self::NumberText { text, .. } => write!(f, "{text}"),
+            Self::String(arg0) => write!(f, "{arg0:?}"),
+            Self::ByteString(arg0) => {
+                write!(f, "b\"")?;
+                for byte in *arg0 {
+                    match byte {
+                        0x20..=0x7e => f.write_char(*byte as char)?,
+                        _ => write!(f, "\\x{byte:02x}")?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Self::Char(arg0) => write!(f, "{arg0:?}"),
+            Self::Ident(arg0) | Self::Keyword(arg0) => write!(f, "{arg0}"),
+            Self::Unknown(arg0) => write!(f, "{arg0}"),
+        }
+    }
+}
+
+impl core::fmt::Debug for Token<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Newline => write!(f, "Token( \\n )"),
+            _ => write!(f, "Token( {self} )"),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub(crate) struct Lexer<'a> {
     input: &'a str,
+    significant_newlines: bool,
+    preserve_number_text: bool,
+    keywords: &'a [&'a str],
 }
 
 impl<'a> Lexer<'a> {
     pub(crate) fn new(input: &'a str) -> Self {
-        Self { input }
+        Self {
+            input,
+            significant_newlines: false,
+            preserve_number_text: false,
+            keywords: &[],
+        }
     }
 
-    fn first<F: Fn(char) -> bool>(&self, condition: F) -> Option<usize> {
-        self.input
-            .char_indices()
-            .find(|(_, c)| condition(*c))
-            .map(|(i, _)| i)
+    /// Whether to produce [`Token::Newline`] for each newline, rather than skipping them like the
+    /// rest of the whitespace. Useful for line-oriented languages.
+    pub(crate) fn significant_newlines(mut self, significant: bool) -> Self {
+        self.significant_newlines = significant;
+        self
     }
-}
 
-impl<'a> Iterator for Lexer<'a> {
-    type Item = Token<'a>;
+    /// Whether to produce [`Token::NumberText`] instead of [`Token::Number`], preserving how each
+    /// number was written in the source. Useful for tools reformatting the source.
+    pub(crate) fn preserve_number_text(mut self, preserve: bool) -> Self {
+        self.preserve_number_text = preserve;
+        self
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
+    /// Identifiers to produce as [`Token::Keyword`] rather than [`Token::Ident`]. No identifier is
+    /// a keyword by default.
+    pub(crate) fn keywords(mut self, keywords: &'a [&'a str]) -> Self {
+        self.keywords = keywords;
+        self
+    }
+
+    /// Skip all the [trivia](Token::is_trivia) tokens, returning only the ones the parser cares
+    /// about. This allows parsing the output of a lexer configured for tooling (for example with
+    /// [`significant_newlines`](Lexer::significant_newlines)) as if it was the default lexer.
+    pub(crate) fn skip_trivia(self) -> impl Iterator<Item = Token<'a>> {
+        self.filter(|token| !token.is_trivia())
+    }
+
+    /// Lex the whole input, returning an error for the first character that is not part of any
+    /// valid token, or for the first malformed literal.
+    pub(crate) fn tokenize_all(input: &'a str) -> Result<Vec<Token<'a>>, Error> {
+        Self::tokenize_with_limit(input, usize::MAX)
+    }
+
+    /// Same as [`Lexer::tokenize_all`], but return an error if the input contains more than
+    /// `max_tokens` tokens, before allocating memory for the rest of them.
+    pub(crate) fn tokenize_with_limit(
+        input: &'a str,
+        max_tokens: usize,
+    ) -> Result<Vec<Token<'a>>, Error> {
+        let mut tokens = Vec::new();
+        let mut lexer = Lexer::new(input);
+        while let Some(token) = lexer.try_next()? {
+            ensure!(
+                tokens.len() < max_tokens,
+                "input contains more than {max_tokens} tokens"
+            );
+            match token {
+                Token::Unknown(c) => bail!("unexpected character {c:?}"),
+                other => tokens.push(other),
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// Return the next token, or an error if the input contains a malformed literal (like an
+    /// unterminated string).
+    pub(crate) fn try_next(&mut self) -> Result<Option<Token<'a>>, Error> {
         loop {
-            let first = self.input.chars().next()?;
+            let Some(first) = self.peek(0) else {
+                return Ok(None);
+            };
 
             if first.is_ascii_digit() {
                 let end = self
                     .first(|c| !c.is_ascii_digit())
                     .unwrap_or(self.input.len());
 
-                let number: i64 = self.input[..end].parse().unwrap();
+                let text = &self.input[..end];
+                let number: i64 = text
+                    .parse()
+                    .map_err(|_| anyhow!("number {text} is too large"))?;
+                self.input = &self.input[end..];
+                if self.preserve_number_text {
+                    return Ok(Some(Token::NumberText {
+                        value: number,
+                        text,
+                    }));
+                }
+                return Ok(Some(Token::Number(number)));
+            }
+
+            if first == 'b' && self.peek(1) == Some('"') {
+                return Ok(Some(Token::ByteString(self.byte_string_literal()?)));
+            }
+
+            if first.is_alphabetic() || first == '_' {
+                let end = self
+                    .first(|c| !c.is_alphanumeric() && c != '_')
+                    .unwrap_or(self.input.len());
+
+                let ident = &self.input[..end];
                 self.input = &self.input[end..];
-                return Some(Token::Number(number));
+                if self.keywords.contains(&ident) {
+                    return Ok(Some(Token::Keyword(ident)));
+                }
+                return Ok(Some(Token::Ident(ident)));
             }
 
-            self.input = &self.input[first.len_utf8()..];
+            self.bump();
 
+            if first == '\n' && self.significant_newlines {
+                return Ok(Some(Token::Newline));
+            }
             if first.is_whitespace() {
                 continue;
             }
             if first == '"' {
-                let end = self.first(|c| c == '"').expect("unterminated string");
+                let end = self
+                    .first(|c| c == '"')
+                    .ok_or_else(|| anyhow!("unterminated string"))?;
 
                 let result = Token::String(&self.input[..end]);
                 self.input = &self.input[end + 1..];
-                return Some(result);
+                return Ok(Some(result));
+            }
+            if first == '\'' {
+                return Ok(Some(Token::Char(self.char_literal()?)));
             }
             match first {
-                '(' => return Some(Token::OpenParen),
-                ')' => return Some(Token::CloseParen),
-                '[' => return Some(Token::OpenSquare),
-                ']' => return Some(Token::CloseSquare),
-                '-' => return Some(Token::Dash),
-                '+' => return Some(Token::Plus),
-                ',' => return Some(Token::Comma),
-                ';' => return Some(Token::Semicolon),
-                '$' => return Some(Token::Dollar),
-                '*' => return Some(Token::Star),
-                '/' => return Some(Token::Slash),
-                _ => panic!("unexpected char: {first}"),
+                '(' => return Ok(Some(Token::OpenParen)),
+                ')' => return Ok(Some(Token::CloseParen)),
+                '[' => return Ok(Some(Token::OpenSquare)),
+                ']' => return Ok(Some(Token::CloseSquare)),
+                '{' => return Ok(Some(Token::OpenBrace)),
+                '}' => return Ok(Some(Token::CloseBrace)),
+                '-' => return Ok(Some(Token::Dash)),
+                '+' => return Ok(Some(Token::Plus)),
+                ',' => return Ok(Some(Token::Comma)),
+                ';' => return Ok(Some(Token::Semicolon)),
+                '$' => return Ok(Some(Token::Dollar)),
+                '*' if self.eat('*') => return Ok(Some(Token::StarStar)),
+                '*' => return Ok(Some(Token::Star)),
+                '/' => return Ok(Some(Token::Slash)),
+                ':' => return Ok(Some(Token::Colon)),
+                '.' if self.eat('.') => return Ok(Some(Token::DotDot)),
+                '.' => return Ok(Some(Token::Dot)),
+                '?' => return Ok(Some(Token::Question)),
+                '!' => return Ok(Some(Token::Bang)),
+                '&' => return Ok(Some(Token::Ampersand)),
+                '|' => return Ok(Some(Token::Pipe)),
+                '=' => return Ok(Some(Token::Equals)),
+                '<' => return Ok(Some(Token::Less)),
+                '>' => return Ok(Some(Token::Greater)),
+                '%' => return Ok(Some(Token::Percent)),
+                '^' => return Ok(Some(Token::Caret)),
+                '~' => return Ok(Some(Token::Tilde)),
+                '@' => return Ok(Some(Token::At)),
+                '#' => return Ok(Some(Token::Hash)),
+                _ => return Ok(Some(Token::Unknown(first))),
+            }
+        }
+    }
+
+    /// Lex the rest of a char literal, after the opening `'` was consumed.
+    fn char_literal(&mut self) -> Result<char, Error> {
+        let mut chars = self.input.chars();
+        let unterminated = || anyhow!("unterminated char literal");
+        let result = match chars.next().ok_or_else(unterminated)? {
+            '\\' => {
+                let escaped = chars.next().ok_or_else(unterminated)?;
+                unescape_char(escaped)
+                    .ok_or_else(|| anyhow!("unknown escape in char literal: \\{escaped}"))?
+            }
+            '\'' => bail!("empty char literal"),
+            other => other,
+        };
+        match chars.next() {
+            Some('\'') => {}
+            Some(_) => bail!("char literal must contain exactly one char"),
+            None => bail!("unterminated char literal"),
+        }
+        self.input = chars.as_str();
+        Ok(result)
+    }
+
+    /// Lex a byte string literal, including the `b"` prefix. Unlike strings, the closing quote
+    /// can be escaped.
+    fn byte_string_literal(&mut self) -> Result<&'a [u8], Error> {
+        let bytes = &self.input.as_bytes()[2..];
+        let mut end = 0;
+        loop {
+            match bytes.get(end) {
+                Some(b'"') => break,
+                Some(b'\\') => end += 2,
+                Some(_) => end += 1,
+                None => bail!("unterminated byte string"),
             }
         }
+        let raw = &bytes[..end];
+        unescape_byte_string(raw)?;
+        self.input = &self.input[end + 3..];
+        Ok(raw)
+    }
+
+    /// Return the char `n` positions ahead, without consuming anything.
+    fn peek(&self, n: usize) -> Option<char> {
+        self.input.chars().nth(n)
+    }
+
+    /// Consume the next char.
+    fn bump(&mut self) -> Option<char> {
+        let next = self.peek(0)?;
+        self.input = &self.input[next.len_utf8()..];
+        Some(next)
+    }
+
+    /// Consume the next char only if it's the expected one, returning whether it was consumed.
+    fn eat(&mut self, expected: char) -> bool {
+        let matches = self.peek(0) == Some(expected);
+        if matches {
+            self.bump();
+        }
+        matches
+    }
+
+    fn first<F: Fn(char) -> bool>(&self, condition: F) -> Option<usize> {
+        self.input
+            .char_indices()
+            .find(|(_, c)| condition(*c))
+            .map(|(i, _)| i)
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Token<'a>;
+
+    /// Return the next token, panicking if the input contains a malformed literal. Use
+    /// [`Lexer::try_next`] to get an error instead.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().unwrap_or_else(|err| panic!("{err}"))
     }
 }
 
@@ -107,7 +683,7 @@ mod tests {
 
     #[test]
     fn test_lex() {
-        let input = "1234  +-,[] ()   \t \"hello world\"69;";
+        let input = "1234  +-,[] (){}   \t \"hello world\"69; foo_bar1";
         let tokens = Lexer::new(input).collect::<Vec<_>>();
         assert_eq!(
             &[
@@ -119,9 +695,364 @@ mod tests {
                 Token::CloseSquare,
                 Token::OpenParen,
                 Token::CloseParen,
+                Token::OpenBrace,
+                Token::CloseBrace,
                 Token::String("hello world"),
                 Token::Number(69),
                 Token::Semicolon,
+                Token::Ident("foo_bar1"),
+            ],
+            tokens.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_punctuation() {
+        let tokens = Lexer::tokenize_all(": . ? ! & | = < > % ^ ~ @ # ; /").unwrap();
+        assert_eq!(
+            vec![
+                Token::Colon,
+                Token::Dot,
+                Token::Question,
+                Token::Bang,
+                Token::Ampersand,
+                Token::Pipe,
+                Token::Equals,
+                Token::Less,
+                Token::Greater,
+                Token::Percent,
+                Token::Caret,
+                Token::Tilde,
+                Token::At,
+                Token::Hash,
+                Token::Semicolon,
+                Token::Slash,
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn test_dot_dot() {
+        let tokens = Lexer::tokenize_all("a.b ..c ...").unwrap();
+        assert_eq!(
+            vec![
+                Token::Ident("a"),
+                Token::Dot,
+                Token::Ident("b"),
+                Token::DotDot,
+                Token::Ident("c"),
+                Token::DotDot,
+                Token::Dot,
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn test_adjacent_single_char_tokens() {
+        let tokens = Lexer::tokenize_all("()[]{},+-;$*/:.?!&|=<>%^~@#").unwrap();
+        assert_eq!(
+            vec![
+                Token::OpenParen,
+                Token::CloseParen,
+                Token::OpenSquare,
+                Token::CloseSquare,
+                Token::OpenBrace,
+                Token::CloseBrace,
+                Token::Comma,
+                Token::Plus,
+                Token::Dash,
+                Token::Semicolon,
+                Token::Dollar,
+                Token::Star,
+                Token::Slash,
+                Token::Colon,
+                Token::Dot,
+                Token::Question,
+                Token::Bang,
+                Token::Ampersand,
+                Token::Pipe,
+                Token::Equals,
+                Token::Less,
+                Token::Greater,
+                Token::Percent,
+                Token::Caret,
+                Token::Tilde,
+                Token::At,
+                Token::Hash,
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let rerender = |input| render(&Lexer::tokenize_all(input).unwrap());
+
+        assert_eq!("[1,2]", rerender("[ 1 , 2 ]"));
+        assert_eq!("1-2", rerender("1 - 2"));
+        assert_eq!("a 1+b", rerender("a 1 + b"));
+        assert_eq!("* *", rerender("* *"));
+        assert_eq!("x. ..y", rerender("x . .. y"));
+        assert_eq!("\"a\"\"b\"'c'", rerender("\"a\" \"b\" 'c'"));
+
+        for input in [
+            "1 2",
+            "a b",
+            "a ** * b",
+            ". . .",
+            "f(x)[0] - -1",
+            "[$(1),*]",
+        ] {
+            let tokens = Lexer::tokenize_all(input).unwrap();
+            assert_eq!(
+                tokens,
+                Lexer::tokenize_all(&render(&tokens)).unwrap(),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_render_negative_numbers() {
+        let tokens = [Token::Number(2), Token::StarStar, Token::Number(-5)];
+        assert_eq!("2**-5", render(&tokens));
+        assert_eq!(
+            "2**(-5)",
+            render_with(&tokens, NegativeNumbers::Parenthesized)
+        );
+
+        let tokens = [Token::Dash, Token::Number(-5), Token::Number(-i64::MAX)];
+        assert_eq!("--5-9223372036854775807", render(&tokens));
+        assert_eq!(
+            "-(-5)(-9223372036854775807)",
+            render_with(&tokens, NegativeNumbers::Parenthesized)
+        );
+    }
+
+    #[test]
+    fn test_stable_hash() {
+        let hash = |input| stable_hash(&Lexer::tokenize_all(input).unwrap());
+
+        assert_eq!(hash("[1, \"a\"]"), hash("[ 1 , \"a\" ]"));
+        // The hash must not change between releases, as it could be persisted.
+        assert_eq!(4817918298004128140, hash("[1, \"a\"]"));
+        assert_ne!(hash("a"), hash("\"a\""));
+        assert_ne!(hash("1 2"), hash("12"));
+    }
+
+    #[test]
+    fn test_significant_newlines() {
+        let lex = |significant| {
+            Lexer::new("1\n2 \r\n\t3")
+                .significant_newlines(significant)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![
+                Token::Number(1),
+                Token::Newline,
+                Token::Number(2),
+                Token::Newline,
+                Token::Number(3),
+            ],
+            lex(true)
+        );
+        assert_eq!(
+            vec![Token::Number(1), Token::Number(2), Token::Number(3)],
+            lex(false)
+        );
+    }
+
+    #[test]
+    fn test_keywords() {
+        let tokens = Lexer::new("if x else iff \"if\"")
+            .keywords(&["if", "else"])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                Token::Keyword("if"),
+                Token::Ident("x"),
+                Token::Keyword("else"),
+                Token::Ident("iff"),
+                Token::String("if"),
+            ],
+            tokens
+        );
+        assert!(tokens[0].is_keyword("if"));
+        assert!(tokens[1].is_keyword("x"));
+        assert_eq!(
+            vec![Token::Ident("if"), Token::Ident("x")],
+            Lexer::new("if x").collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_skip_trivia() {
+        let input = "[1,\n  2]\n\n(foo\r\n+ \"a\\nb\")\n";
+        let filtered = Lexer::new(input)
+            .significant_newlines(true)
+            .skip_trivia()
+            .collect::<Vec<_>>();
+        assert_eq!(Lexer::new(input).collect::<Vec<_>>(), filtered);
+        assert!(!filtered.iter().any(|token| token.is_trivia()));
+        assert!(Token::Newline.is_trivia());
+    }
+
+    #[test]
+    fn test_tokenize_all() {
+        assert_eq!(
+            vec![Token::Number(1), Token::Plus, Token::Number(2)],
+            Lexer::tokenize_all("1 + 2").unwrap()
+        );
+        let err = Lexer::tokenize_all("1 ` 2").unwrap_err();
+        assert_eq!("unexpected character '`'", err.to_string());
+    }
+
+    #[test]
+    fn test_tokenize_with_limit() {
+        assert!(Lexer::tokenize_with_limit("1 + 2", 3).is_ok());
+        let err = Lexer::tokenize_with_limit("1 + 2 + 3", 3).unwrap_err();
+        assert_eq!("input contains more than 3 tokens", err.to_string());
+        let err = Lexer::tokenize_with_limit("99999999999999999999", 3).unwrap_err();
+        assert_eq!("number 99999999999999999999 is too large", err.to_string());
+    }
+
+    #[test]
+    fn test_lex_char() {
+        let tokens = Lexer::new("'a' '\\n' '\\''").collect::<Vec<_>>();
+        assert_eq!(
+            &[Token::Char('a'), Token::Char('\n'), Token::Char('\'')],
+            tokens.as_slice()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "char literal must contain exactly one char")]
+    fn test_lex_char_multiple_chars() {
+        Lexer::new("'ab'").for_each(drop);
+    }
+
+    #[test]
+    #[should_panic(expected = "unterminated char literal")]
+    fn test_lex_char_unterminated() {
+        Lexer::new("'a").for_each(drop);
+    }
+
+    #[test]
+    fn test_preserve_number_text() {
+        let mut lexer = Lexer::new("007 + 7").preserve_number_text(true);
+        let token = lexer.next().unwrap();
+        assert_eq!(
+            Token::NumberText {
+                value: 7,
+                text: "007"
+            },
+            token
+        );
+        assert_eq!(Some(7), token.as_number());
+        assert_eq!("007", token.to_string());
+        assert_eq!(3, token.len_in_source());
+        assert_eq!(TokenKind::Number, token.kind());
+        assert_eq!(Some(Token::Plus), lexer.next());
+
+        assert_eq!(Some(Token::Number(7)), Lexer::new("007").next());
+    }
+
+    #[test]
+    fn test_lex_byte_string() {
+        let tokens = Lexer::tokenize_all("b\"abc\" b bar b\"\"").unwrap();
+        assert_eq!(
+            vec![
+                Token::ByteString(b"abc"),
+                Token::Ident("b"),
+                Token::Ident("bar"),
+                Token::ByteString(b""),
+            ],
+            tokens
+        );
+        assert_eq!(Some(b"abc".to_vec()), tokens[0].byte_string_value());
+        assert_eq!("b\"abc\"", tokens[0].to_string());
+    }
+
+    #[test]
+    fn test_lex_byte_string_escapes() {
+        let tokens = Lexer::tokenize_all(r#"b"\xff\x00\"\n\\" 1"#).unwrap();
+        assert_eq!(
+            vec![Token::ByteString(br#"\xff\x00\"\n\\"#), Token::Number(1)],
+            tokens
+        );
+        assert_eq!(
+            Some(vec![0xff, 0x00, b'"', b'\n', b'\\']),
+            tokens[0].byte_string_value()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid \\x escape in byte string")]
+    fn test_lex_byte_string_invalid_escape() {
+        Lexer::new(r#"b"\xzz""#).for_each(drop);
+    }
+
+    #[test]
+    fn test_len_in_source() {
+        let input = "( 42 \"hi\" foo 'a' '\\n'";
+        let lengths = Lexer::new(input)
+            .map(|token| token.len_in_source())
+            .collect::<Vec<_>>();
+        assert_eq!(&[1, 2, 4, 3, 3, 4], lengths.as_slice());
+    }
+
+    #[test]
+    fn test_operators() {
+        assert!(Token::Plus.is_binary_op());
+        assert!(Token::Dash.is_binary_op());
+        assert!(!Token::Number(1).is_binary_op());
+
+        assert!(Token::Dash.is_unary_op());
+        assert!(!Token::Plus.is_unary_op());
+        assert!(!Token::Number(1).is_unary_op());
+    }
+
+    #[test]
+    fn test_star_star() {
+        let tokens = Lexer::tokenize_all("2 ** 3 * 4 ***").unwrap();
+        assert_eq!(
+            vec![
+                Token::Number(2),
+                Token::StarStar,
+                Token::Number(3),
+                Token::Star,
+                Token::Number(4),
+                Token::StarStar,
+                Token::Star,
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn test_token_ordering() {
+        let mut tokens = vec![
+            Token::String("b"),
+            Token::Number(3),
+            Token::Comma,
+            Token::Number(-10),
+            Token::String("a"),
+            Token::OpenParen,
+            Token::Number(0),
+        ];
+        tokens.sort();
+        assert_eq!(
+            &[
+                Token::OpenParen,
+                Token::Comma,
+                Token::Number(-10),
+                Token::Number(0),
+                Token::Number(3),
+                Token::String("a"),
+                Token::String("b"),
             ],
             tokens.as_slice()
         );
@@ -1,7 +1,15 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
 mod expansion;
 mod lexer;
 mod parser;
+#[cfg(test)]
+mod replay;
+#[cfg(feature = "proc-macro2")]
+mod rust_tokens;
 mod streams;
 
 pub use parser::*;
-pub use streams::Streams;
+pub use streams::{StreamId, StreamSnapshot, Streams, StreamsSnapshot};
@@ -0,0 +1,133 @@
+use crate::parser::state::PrimaryInput;
+use alloc::sync::Arc;
+use core::fmt::{Debug, Formatter};
+
+/// Options changing how the parser behaves. The default configuration is the one used by
+/// [`State::new`](crate::State::new), and individual options can be changed with the builder
+/// methods:
+///
+/// ```
+/// # use parsibes::ParseConfig;
+/// let config = ParseConfig::new().allow_trailing_comma(false);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ParseConfig {
+    pub(super) separator_policy: SeparatorPolicy,
+    pub(super) allow_identifiers: bool,
+    pub(super) max_iterations: Option<usize>,
+    pub(super) trace_diverge: bool,
+    pub(super) recover_array_elements: bool,
+    pub(super) concatenate_strings: bool,
+    pub(super) primary_hook: Option<PrimaryHook>,
+}
+
+impl ParseConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether a comma is allowed after the last element of an array, a tuple, a map or the
+    /// arguments of a call. Enabled by default.
+    pub fn allow_trailing_comma(mut self, allow: bool) -> Self {
+        self.separator_policy.allow_trailing = allow;
+        self
+    }
+
+    /// Whether bare identifiers are accepted as expressions (for example to refer to variables).
+    /// Enabled by default.
+    pub fn allow_identifiers(mut self, allow: bool) -> Self {
+        self.allow_identifiers = allow;
+        self
+    }
+
+    /// Maximum number of iterations a single parsing loop can do before parsing is aborted with
+    /// an error. Unlimited by default.
+    pub fn max_iterations(mut self, max: Option<usize>) -> Self {
+        self.max_iterations = max;
+        self
+    }
+
+    /// Whether adjacent string literals (like `"a" "b"`) are accepted as a single expression,
+    /// implicitly concatenating them. Disabled by default.
+    pub fn concatenate_strings(mut self, concatenate: bool) -> Self {
+        self.concatenate_strings = concatenate;
+        self
+    }
+
+    /// Extend the primary expressions (the operands of the operators) with custom syntax. The hook
+    /// is invoked for each stream whose next token doesn't start an array, a map, a parenthesized
+    /// expression or a string, before falling back to numbers and identifiers. It returns how many
+    /// tokens the custom primary is made of, which are then consumed, or zero to parse the stream
+    /// as usual.
+    ///
+    /// ```
+    /// # use parsibes::{parse_expression, ParseConfig, State, Streams};
+    /// // Accept `@name` as a primary expression.
+    /// let config = ParseConfig::new().primary_hook(|input| match input.peek(0).as_deref() {
+    ///     Some("@") => 2,
+    ///     _ => 0,
+    /// });
+    /// let mut streams = Streams::new();
+    /// streams.add("@foo + 1");
+    /// parse_expression(&mut State::with_config(streams, config)).unwrap();
+    /// ```
+    pub fn primary_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&mut PrimaryInput<'_, '_>) -> usize + Send + Sync + 'static,
+    {
+        self.primary_hook = Some(PrimaryHook(Arc::new(hook)));
+        self
+    }
+
+    /// Whether to keep parsing when an array element fails to parse, skipping to the next
+    /// element. The errors are then available through
+    /// [`State::recovered_errors`](crate::State::recovered_errors). Disabled by default.
+    pub fn recover_array_elements(mut self, recover: bool) -> Self {
+        self.recover_array_elements = recover;
+        self
+    }
+
+    /// Whether to record which branch each stream took every time the parser diverges, which
+    /// can then be retrieved with [`State::diverge_trace`](crate::State::diverge_trace). Useful
+    /// to debug ambiguous grammars. Disabled by default.
+    pub fn trace_diverge(mut self, trace: bool) -> Self {
+        self.trace_diverge = trace;
+        self
+    }
+}
+
+impl Default for ParseConfig {
+    fn default() -> Self {
+        Self {
+            separator_policy: SeparatorPolicy {
+                allow_trailing: true,
+            },
+            allow_identifiers: true,
+            max_iterations: None,
+            trace_diverge: false,
+            recover_array_elements: false,
+            concatenate_strings: false,
+            primary_hook: None,
+        }
+    }
+}
+
+/// Hook set with [`ParseConfig::primary_hook`], shared between the clones of the configuration.
+/// The hook must be `Send` and `Sync` to keep the configuration `Send` and `Sync`.
+#[derive(Clone)]
+pub(super) struct PrimaryHook(
+    pub(super) Arc<dyn Fn(&mut PrimaryInput<'_, '_>) -> usize + Send + Sync>,
+);
+
+impl Debug for PrimaryHook {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str("PrimaryHook")
+    }
+}
+
+/// Rules for comma separated lists, shared by all the parsers of comma separated lists through
+/// [`parse_separated`](super::helpers::parse_separated).
+#[derive(Debug, Clone, Copy)]
+pub(super) struct SeparatorPolicy {
+    pub(super) allow_trailing: bool,
+}
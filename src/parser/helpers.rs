@@ -1,23 +1,79 @@
 use crate::lexer::Token;
 use crate::parser::state::State;
 use crate::streams::{PauseId, StreamId};
-use anyhow::Error;
-use std::collections::BTreeMap;
+use alloc::format;
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+use anyhow::{anyhow, bail, Error};
+use core::fmt::Display;
 
 /// Execute the closure repeatedly until all streams are paused, and then unpause the [`ParseId`]
-/// provided as an argument to the closure.
+/// provided as an argument to the closure. The closure is never executed if all streams are
+/// already paused (or if there are no streams at all).
+///
+/// An error is returned if the closure is executed more times than
+/// [`ParseConfig::max_iterations`](crate::ParseConfig::max_iterations) allows.
 pub(super) fn while_any_unpaused<'a, F>(state: &mut State<'a>, mut f: F) -> Result<(), Error>
 where
     F: FnMut(&mut State<'a>, PauseId) -> Result<(), Error>,
 {
-    let pause = PauseId::new();
+    let pause = state.new_pause_id();
+    let mut iterations = 0;
     while state.is_any_unpaused() {
+        if let Some(max) = state.config.max_iterations {
+            if iterations >= max {
+                bail!("exceeded the maximum of {max} iterations");
+            }
+        }
+        iterations += 1;
+
         f(state, pause)?;
     }
     state.unpause(pause);
     Ok(())
 }
 
+/// Parse the rest of a comma separated list terminated by `close`, calling `element` to parse each
+/// element. This must be called after the first element and the comma following it were consumed,
+/// and whether a trailing comma is accepted depends on the
+/// [`SeparatorPolicy`](super::config::SeparatorPolicy) in the configuration.
+///
+/// The `end` description (like `"end of array"`) is used in errors and pause reasons.
+pub(super) fn parse_separated<'a, F>(
+    state: &mut State<'a>,
+    end: &'static str,
+    close: Token<'a>,
+    mut element: F,
+) -> Result<(), Error>
+where
+    F: FnMut(&mut State<'a>) -> Result<(), Error>,
+{
+    let policy = state.config.separator_policy;
+    while_any_unpaused(state, |state, pause| {
+        // The list can end right after a comma only if trailing commas are allowed.
+        state.peek_token(|peek| {
+            if peek.peek_is(&close) {
+                if !policy.allow_trailing {
+                    peek.fail(&format!("trailing comma not allowed before `{close}`"));
+                    return;
+                }
+                peek.consume();
+                peek.pause_with_reason(pause, end);
+            }
+        })?;
+
+        element(state)?;
+
+        state.next_token(|next| match &next.token {
+            Token::Comma => {}
+            token if *token == close => next.pause_with_reason(pause, end),
+            _ => next.mismatch(&format!("{end} or comma")),
+        })?;
+        Ok(())
+    })
+}
+
 /// The [`Diverge`] struct allows to execute different parsing functions depending on the contents
 /// of each stream. This is useful for example when parsing an expression, as there are multiple
 /// kinds of expressions with different parsing rules.
@@ -27,50 +83,86 @@ where
 /// group ID, to provide the logic for how to handle that group.
 ///
 /// Under the hood, when handling a specific group ID, all other streams are paused.
-pub(super) struct Diverge<'src, 'state, K: Ord> {
-    groups: BTreeMap<K, Vec<StreamId>>,
+///
+/// Once all groups are handled [`Diverge::finish`] must be called, which checks that no stream was
+/// left in a group without a handler (as that stream would never make progress).
+///
+/// Groups are handled in the order [`Diverge::handle`] is called, regardless of how the keys would
+/// sort, and groups are otherwise kept in the order they were first seen.
+///
+/// If no stream is unpaused there are no groups, so none of the handlers is executed and
+/// [`Diverge::finish`] succeeds.
+pub(super) struct Diverge<'src, 'state, K: PartialEq + Display> {
+    groups: Vec<(K, Vec<StreamId>)>,
+    handled: Vec<K>,
     state: &'state mut State<'src>,
 }
 
-impl<'src, 'state, K: Ord> Diverge<'src, 'state, K> {
+impl<'src, 'state, K: PartialEq + Display> Diverge<'src, 'state, K> {
+    /// Group the streams based on their next token. As the grouper receives the token with the
+    /// lifetime of the source, keys can borrow from the token contents (for example to group
+    /// identifiers by their name).
     pub(super) fn new<G>(state: &'state mut State<'src>, mut grouper: G) -> Result<Self, Error>
     where
-        G: FnMut(&Token<'_>) -> K,
+        G: FnMut(&Token<'src>) -> K,
     {
-        let mut groups = BTreeMap::new();
-        state.peek_token(|peek| {
-            if let Some(token) = &peek.token {
-                groups
-                    .entry(grouper(token))
-                    .or_insert_with(Vec::new)
-                    .push(peek.stream_id())
+        let mut groups: Vec<(K, Vec<StreamId>)> = Vec::new();
+        state.peek_token(|peek| match &peek.token {
+            Some(token) => {
+                let key = grouper(token);
+                match groups.iter_mut().find(|(existing, _)| *existing == key) {
+                    Some((_, ids)) => ids.push(peek.stream_id()),
+                    None => groups.push((key, vec![peek.stream_id()])),
+                }
             }
+            // Streams without tokens left wouldn't be part of any group, and would silently
+            // skip all the handlers.
+            None => peek.error(anyhow!("end of input")),
         })?;
-        Ok(Self { groups, state })
+        #[cfg(test)]
+        for (key, streams) in &groups {
+            state.streams.record(crate::replay::ReplayEvent::Diverge {
+                group: key.to_string(),
+                streams: streams.clone(),
+            });
+        }
+        Ok(Self {
+            groups,
+            handled: Vec::new(),
+            state,
+        })
     }
 
     pub(super) fn handle<F>(mut self, case: K, handler: F) -> Result<Self, Error>
     where
         F: FnOnce(&mut State<'src>) -> Result<(), Error>,
     {
-        let Some(group) = self.groups.remove(&case) else {
+        if self.handled.contains(&case) {
+            bail!("diverge: group `{case}` is handled more than once");
+        }
+        let position = self.groups.iter().position(|(key, _)| *key == case);
+        let Some((_, group)) = position.map(|p| self.groups.remove(p)) else {
+            self.handled.push(case);
             return Ok(self);
         };
 
-        let pause = PauseId::new();
-        for stream in self.state.streams.iter_mut() {
-            if !group.contains(&stream.id()) {
-                stream.pause(pause);
+        if self.state.config.trace_diverge {
+            for id in &group {
+                self.state.diverge_trace.push((*id, case.to_string()));
             }
         }
+        self.handled.push(case);
 
-        handler(self.state)?;
+        self.state.isolate(&group, handler)?;
+        Ok(self)
+    }
 
-        for stream in self.state.streams.iter_mut() {
-            stream.maybe_unpause(pause);
+    /// Ensure all the groups were handled.
+    pub(super) fn finish(self) -> Result<(), Error> {
+        if let Some((case, _)) = self.groups.first() {
+            bail!("diverge: no handler for group `{case}`");
         }
-
-        Ok(self)
+        Ok(())
     }
 }
 
@@ -81,6 +173,231 @@ macro_rules! diverge {
             $($pat => stringify!($pat),)*
         })?
         $(.handle(stringify!($pat), |$state_binding| $block)?)*
-        ;
+        .finish()?;
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streams::Streams;
+    use crate::ParseConfig;
+
+    #[test]
+    fn test_diverge_duplicate_group() {
+        let mut state = state(&["1", "\"hello\""]);
+        let err = Diverge::new(&mut state, |_| "same")
+            .unwrap()
+            .handle("same", |_| Ok(()))
+            .and_then(|d| d.handle("same", |_| Ok(())))
+            .err()
+            .unwrap();
+        assert_eq!(
+            "diverge: group `same` is handled more than once",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_diverge_unhandled_group() {
+        let mut state = state(&["1", "\"hello\""]);
+        let err = Diverge::new(&mut state, |token| match token {
+            Token::Number(_) => "number",
+            _ => "other",
+        })
+        .unwrap()
+        .handle("number", |state| state.expect(Token::Number(1)))
+        .unwrap()
+        .finish()
+        .unwrap_err();
+        assert_eq!("diverge: no handler for group `other`", err.to_string());
+    }
+
+    #[test]
+    fn test_diverge_order() {
+        let mut state = state(&["1", "b", "a", "2"]);
+        let mut order = Vec::new();
+        Diverge::new(&mut state, |token| match token {
+            Token::Ident(name) => *name,
+            _ => "number",
+        })
+        .unwrap()
+        .handle("b", |state| {
+            order.push("b");
+            state.expect(Token::Ident("b"))
+        })
+        .unwrap()
+        .handle("number", |state| {
+            order.push("number");
+            state.expect_pred(|t| t.as_number().is_some(), "number")
+        })
+        .unwrap()
+        .handle("a", |state| {
+            order.push("a");
+            state.expect(Token::Ident("a"))
+        })
+        .unwrap()
+        .finish()
+        .unwrap();
+        assert_eq!(vec!["b", "number", "a"], order);
+    }
+
+    #[test]
+    fn test_diverge_spawn_stream() {
+        let mut state = state(&["1 2", "x 3"]);
+        let mut spawned = None;
+        Diverge::new(&mut state, |token| match token {
+            Token::Number(_) => "number",
+            _ => "other",
+        })
+        .unwrap()
+        .handle("number", |state| {
+            state.expect(Token::Number(1))?;
+            spawned = Some(state.spawn_stream("4"));
+            state.expect_pred(|t| t.as_number().is_some(), "number")
+        })
+        .unwrap()
+        // The spawned stream has no tokens left, so this would fail if it wasn't paused.
+        .handle("other", |state| state.expect(Token::Ident("x")))
+        .unwrap()
+        .finish()
+        .unwrap();
+
+        let snapshot = state.streams().snapshot();
+        let positions = snapshot.streams.iter().map(|s| s.position);
+        assert_eq!(vec![2, 1, 1], positions.collect::<Vec<_>>());
+        assert!(snapshot.streams.iter().all(|s| s.paused_by.is_empty()));
+        assert!(state.stream(spawned.unwrap()).is_finished());
+    }
+
+    #[test]
+    fn test_diverge_no_streams() {
+        let mut state = state(&[]);
+        Diverge::new(&mut state, |_| "any")
+            .unwrap()
+            .handle("any", |_| panic!("no stream should be handled"))
+            .unwrap()
+            .finish()
+            .unwrap();
+
+        let mut iterations = 0;
+        while_any_unpaused(&mut state, |_, _| {
+            iterations += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(0, iterations);
+    }
+
+    #[test]
+    fn test_diverge_unhandled_order() {
+        // Unhandled groups are reported in the order they were first seen.
+        let mut state = state(&["1", "b", "a"]);
+        let err = Diverge::new(&mut state, |token| match token {
+            Token::Ident(name) => *name,
+            _ => "number",
+        })
+        .unwrap()
+        .handle("number", |state| state.expect(Token::Number(1)))
+        .unwrap()
+        .finish()
+        .unwrap_err();
+        assert_eq!("diverge: no handler for group `b`", err.to_string());
+    }
+
+    #[test]
+    fn test_diverge_by_number() {
+        let mut state = state(&["12", "1", "x", "5"]);
+        let mut handled = Vec::new();
+        // Ranges of numbers can be grouped by returning a key for each bucket.
+        Diverge::new(&mut state, |token| match token.as_number() {
+            Some(..=9) => "<10",
+            Some(10..) => ">=10",
+            None => "other",
+        })
+        .unwrap()
+        .handle("<10", |state| {
+            handled.push(state.unpaused_ids().len());
+            state.expect_pred(|t| t.as_number().is_some_and(|n| n < 10), "small number")
+        })
+        .unwrap()
+        .handle(">=10", |state| {
+            handled.push(state.unpaused_ids().len());
+            state.expect(Token::Number(12))
+        })
+        .unwrap()
+        .handle("other", |state| state.expect(Token::Ident("x")))
+        .unwrap()
+        .finish()
+        .unwrap();
+        assert_eq!(vec![2, 1], handled);
+    }
+
+    #[test]
+    fn test_diverge_on_token_contents() {
+        let mut state = state(&["foo 1", "bar \"hello\"", "foo 2"]);
+        Diverge::new(&mut state, |token| match token {
+            Token::Ident(name) => *name,
+            _ => "other",
+        })
+        .unwrap()
+        .handle("foo", |state| {
+            state.expect(Token::Ident("foo"))?;
+            state.expect_pred(|t| t.as_number().is_some(), "number")
+        })
+        .unwrap()
+        .handle("bar", |state| {
+            state.expect(Token::Ident("bar"))?;
+            state.expect(Token::String("hello"))
+        })
+        .unwrap()
+        .finish()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_parse_separated() {
+        // Parse a parenthesized list, checking it follows the same rules as arrays.
+        let parse = |input: &'static str, allow_trailing: bool| {
+            let config = ParseConfig::new().allow_trailing_comma(allow_trailing);
+            let mut streams = Streams::new();
+            streams.add(input);
+            let mut state = State::with_config(streams, config);
+            state.expect(Token::OpenParen)?;
+            state.expect(Token::Number(1))?;
+            state.expect(Token::Comma)?;
+            parse_separated(&mut state, "end of tuple", Token::CloseParen, |state| {
+                state.expect_pred(|t| t.as_number().is_some(), "number")
+            })
+        };
+        for allow_trailing in [true, false] {
+            parse("(1, 2)", allow_trailing).unwrap();
+            parse("(1, 2, 3)", allow_trailing).unwrap();
+            assert_eq!(
+                parse("(1, 2,)", allow_trailing).is_ok(),
+                crate::parse_array(&mut array_state("[1, 2,]", allow_trailing)).is_ok(),
+            );
+        }
+        assert!(parse("(1, 2,)", true).is_ok());
+        assert_eq!(
+            "expected end of tuple or comma, found Token( 3 )",
+            parse("(1, 2 3)", true)
+                .unwrap_err()
+                .root_cause()
+                .to_string()
+        );
+    }
+
+    fn array_state(input: &'static str, allow_trailing: bool) -> State<'static> {
+        let mut streams = Streams::new();
+        streams.add(input);
+        State::with_config(
+            streams,
+            ParseConfig::new().allow_trailing_comma(allow_trailing),
+        )
+    }
+
+    fn state(inputs: &[&'static str]) -> State<'static> {
+        State::from_programs(inputs.iter().copied())
+    }
+}
@@ -1,69 +1,335 @@
+mod config;
 mod helpers;
 mod state;
 
 use crate::diverge;
-use crate::lexer::Token;
-use crate::parser::helpers::while_any_unpaused;
-pub use crate::parser::state::State;
-use crate::streams::PauseId;
+use crate::lexer::{Token, TokenKind};
+pub use crate::parser::config::ParseConfig;
+use crate::parser::helpers::{parse_separated, while_any_unpaused};
+pub use crate::parser::state::{
+    Diagnostic, ParseResults, PrimaryInput, State, StreamHandle, TokenEvent,
+};
+use crate::streams::{StreamId, Streams};
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::vec::Vec;
 use anyhow::Error;
 
+/// Try parsing the same source with each of the provided parsers, returning the indexes of the
+/// parsers that parsed the whole source without errors. The source is added as a separate stream
+/// for each parser, so all of them run in parallel.
+///
+/// ```
+/// # use parsibes::{matching_grammars, parse_array, parse_expression};
+/// let matching = matching_grammars("1 + 2", &[parse_array, parse_expression]).unwrap();
+/// assert_eq!(vec![1], matching);
+/// ```
+pub fn matching_grammars(source: &str, grammars: &[Grammar]) -> Result<Vec<usize>, Error> {
+    let mut streams = Streams::new();
+    let ids = grammars
+        .iter()
+        .map(|_| streams.add(source))
+        .collect::<Vec<_>>();
+    let mut state = State::new(streams);
+
+    let parsers = ids.iter().copied().zip(grammars.iter().copied()).collect();
+    let results = state.parse_per_stream(parsers)?;
+    Ok(ids
+        .iter()
+        .enumerate()
+        .filter(|(_, id)| results.completed.contains(id) && state.stream(**id).is_finished())
+        .map(|(index, _)| index)
+        .collect())
+}
+
+/// A parser for a whole grammar, like [`parse_array`] or [`parse_expression`].
+pub type Grammar = fn(&mut State<'_>) -> Result<(), Error>;
+
 pub fn parse_expression(state: &mut State<'_>) -> Result<(), Error> {
+    parse_expression_until(state, &[])
+}
+
+/// Parse an expression, stopping before any of the `stop` tokens even if they would otherwise
+/// continue the expression (like a binary operator). The stop token is not consumed.
+pub(crate) fn parse_expression_until(
+    state: &mut State<'_>,
+    stop: &[Token<'_>],
+) -> Result<(), Error> {
     // An iteration of this loop parses one value and optionally a binary operator. By looping we
     // can parse arbitrarily long expressions, as they will continue to loop until paused.
     while_any_unpaused(state, |state, pause| {
+        // Each iteration starts expecting an operand, so a `-` here is a unary operator applied
+        // to it rather than a binary one (which can only follow an operand, see below). Multiple
+        // unary operators can be stacked, like in `--1`, which is warned about as it looks like a
+        // decrement.
+        let mut stacked = 0;
+        loop {
+            let mut unary = false;
+            state.peek_token(|peek| match peek.token {
+                Some(token) if token.is_unary_op() => {
+                    if stacked == 1 {
+                        peek.warn("use of a double negation, which is not a decrement");
+                    }
+                    peek.consume_operator(Some("unary"));
+                    unary = true;
+                }
+                _ => {}
+            })?;
+            if !unary {
+                break;
+            }
+            stacked += 1;
+        }
+
         // Different kinds of expressions require different parsing rules:
         diverge!(match state {
             Token::OpenSquare => |state| parse_array(state),
-            Token::OpenParen => |state| {
-                state.expect(Token::OpenParen)?;
-                parse_expression(state)?;
-                state.expect(Token::CloseParen)?;
-
+            Token::OpenParen => |state| parse_parenthesized(state),
+            Token::OpenBrace => |state| parse_map(state),
+            Token::String(_) => |state| {
+                state.expect_pred(|token| matches!(token, Token::String(_)), "string")?;
+                if state.config.concatenate_strings {
+                    while_any_unpaused(state, |state, pause| {
+                        state.peek_token(|peek| match &peek.token {
+                            Some(Token::String(_)) => peek.consume(),
+                            _ => peek.pause_with_reason(pause, "end of strings"),
+                        })
+                    })?;
+                }
                 Ok(())
             },
             _ => |state| {
-                state.next_token(|next| match &next.token {
-                    Token::Number(_) => {}
-                    Token::String(_) => {}
-                    _ => next.mismatch("expression"),
-                })
+                // Streams with a custom primary are paused while the rest parse the default ones.
+                let custom = match state.config.primary_hook.clone() {
+                    Some(hook) => {
+                        let custom = state.new_pause_id();
+                        state.peek_token(|peek| {
+                            if peek.consume_custom_primary(&hook) {
+                                peek.pause_with_reason(custom, "custom primary");
+                            }
+                        })?;
+                        Some(custom)
+                    }
+                    None => None,
+                };
+                let allow_identifiers = state.config.allow_identifiers;
+                state.expect_pred(
+                    |token| match token {
+                        Token::Number(_) | Token::NumberText { .. } => true,
+                        Token::Ident(_) => allow_identifiers,
+                        _ => false,
+                    },
+                    "expression",
+                )?;
+                if let Some(custom) = custom {
+                    state.unpause(custom);
+                }
+                Ok(())
             },
         });
 
+        // Postfix indexing and calls, like `a[0][1]` or `f(1)(2)`. Array literals and nested
+        // expressions can only be at the start of an expression, so a `[` or `(` after a value
+        // always indexes into it or calls it.
+        while_any_unpaused(state, |state, pause| {
+            let mut calls = Vec::new();
+            state.peek_token(|peek| match &peek.token {
+                Some(Token::OpenSquare) => peek.consume(),
+                Some(Token::OpenParen) => {
+                    peek.consume();
+                    calls.push(peek.stream_id());
+                }
+                _ => peek.pause_with_reason(pause, "end of postfix"),
+            })?;
+
+            if !calls.is_empty() {
+                state.isolate(&calls, parse_call_arguments)?;
+            }
+            let indexing = state
+                .unpaused_ids()
+                .into_iter()
+                .filter(|id| !calls.contains(id))
+                .collect::<Vec<_>>();
+            if !indexing.is_empty() {
+                state.isolate(&indexing, |state| {
+                    parse_expression(state)?;
+                    state.expect(Token::CloseSquare)
+                })?;
+            }
+            Ok(())
+        })?;
+
         // As we don't need to return an AST, we don't need to do the nested recursive functions to
         // handle precedence, we can just parse one operator after another.
+        let mut power = Vec::new();
+        let mut ternary = Vec::new();
         state.peek_token(|peek| match &peek.token {
-            Some(Token::Dash) => peek.consume(),
-            Some(Token::Plus) => peek.consume(),
+            Some(token) if stop.contains(token) => peek.pause_with_reason(pause, "stop token"),
+            Some(token) if token.is_binary_op() => {
+                peek.consume_operator(Some("binary"));
+            }
+            Some(Token::StarStar) => {
+                peek.consume_operator(Some("binary"));
+                power.push(peek.stream_id());
+            }
+            // There are no postfix operators, so a `?` after a value always starts a ternary.
+            Some(Token::Question) => {
+                peek.consume_operator(None);
+                ternary.push(peek.stream_id());
+            }
             // Next token is not a binary operator, stop parsing this expression.
-            _ => peek.pause(pause),
+            _ => peek.pause_with_reason(pause, "end of expression"),
         })?;
 
+        // The power operator is right-associative, so its right operand is the rest of the
+        // expression: parse it recursively rather than continuing the loop.
+        if !power.is_empty() {
+            state.isolate(&power, |state| parse_expression_until(state, stop))?;
+        }
+
+        // The ternary operator has the lowest precedence, so the condition is everything parsed
+        // so far and the else branch is the rest of the expression. The then branch stops at the
+        // first `:`, as any nested ternary in it consumes its own `:` before returning.
+        if !ternary.is_empty() {
+            state.isolate(&ternary, |state| {
+                parse_expression_until(state, &[Token::Colon])?;
+                state.expect(Token::Colon)?;
+                parse_expression_until(state, stop)
+            })?;
+        }
+
+        for stream in state.streams.iter_mut() {
+            if power.contains(&stream.id()) || ternary.contains(&stream.id()) {
+                stream.pause_with_reason(pause, "end of expression");
+            }
+        }
+
         Ok(())
     })?;
 
     Ok(())
 }
 
+/// Parse a parenthesized expression like `(1 + 2)`, or a tuple like `(1, 2)` if the first
+/// expression is followed by a comma.
+fn parse_parenthesized(state: &mut State<'_>) -> Result<(), Error> {
+    let pause = state.new_pause_id();
+
+    state.expect(Token::OpenParen)?;
+    parse_expression(state)?;
+    state.next_token(|next| match &next.token {
+        Token::CloseParen => next.pause_with_reason(pause, "end of parentheses"),
+        Token::Comma => {}
+        _ => next.mismatch("end of expression or comma"),
+    })?;
+    parse_separated(state, "end of tuple", Token::CloseParen, parse_expression)?;
+
+    state.unpause(pause);
+    Ok(())
+}
+
+/// Parse a map like `{1: 2, 3: 4}`, where both the keys and the values are expressions.
+fn parse_map(state: &mut State<'_>) -> Result<(), Error> {
+    let pause = state.new_pause_id();
+
+    state.expect(Token::OpenBrace)?;
+    state.peek_token(|peek| {
+        if peek.peek_kind() == Some(TokenKind::CloseBrace) {
+            peek.consume();
+            peek.pause_with_reason(pause, "empty map");
+        }
+    })?;
+
+    parse_map_entry(state)?;
+    state.next_token(|next| match &next.token {
+        Token::CloseBrace => next.pause_with_reason(pause, "end of map"),
+        Token::Comma => {}
+        _ => next.mismatch("end of map or comma"),
+    })?;
+    parse_separated(state, "end of map", Token::CloseBrace, parse_map_entry)?;
+
+    state.unpause(pause);
+    Ok(())
+}
+
+fn parse_map_entry(state: &mut State<'_>) -> Result<(), Error> {
+    // The key stops at the `:`, rather than treating it as the end of a ternary.
+    parse_expression_until(state, &[Token::Colon])?;
+    state.expect(Token::Colon)?;
+    parse_expression(state)
+}
+
+/// Parse the arguments of a function call, after the opening `(` was consumed.
+fn parse_call_arguments(state: &mut State<'_>) -> Result<(), Error> {
+    let pause = state.new_pause_id();
+
+    state.peek_token(|peek| {
+        if peek.peek_kind() == Some(TokenKind::CloseParen) {
+            peek.consume();
+            peek.pause_with_reason(pause, "no arguments");
+        }
+    })?;
+
+    parse_expression(state)?;
+    state.next_token(|next| match &next.token {
+        Token::CloseParen => next.pause_with_reason(pause, "end of arguments"),
+        Token::Comma => {}
+        _ => next.mismatch("end of arguments or comma"),
+    })?;
+    parse_separated(
+        state,
+        "end of arguments",
+        Token::CloseParen,
+        parse_expression,
+    )?;
+
+    state.unpause(pause);
+    Ok(())
+}
+
 pub fn parse_array(state: &mut State<'_>) -> Result<(), Error> {
-    let pause = PauseId::new();
+    parse_array_inner(state, &mut ArrayLengths::default())
+}
+
+/// Parse an array like [`parse_array`] does, additionally reporting how many elements the array
+/// had in each stream.
+pub fn parse_array_with_lengths(state: &mut State<'_>) -> Result<ArrayLengths, Error> {
+    let mut lengths = ArrayLengths::default();
+    parse_array_inner(state, &mut lengths)?;
+    Ok(lengths)
+}
+
+fn parse_array_inner(state: &mut State<'_>, lengths: &mut ArrayLengths) -> Result<(), Error> {
+    let pause = state.new_pause_id();
 
     state.expect(Token::OpenSquare)?;
 
     // Empty array
     state.peek_token(|peek| {
-        if let Some(Token::CloseSquare) = &peek.token {
+        if peek.peek_kind() == Some(TokenKind::CloseSquare) {
             peek.consume();
-            peek.pause(pause);
+            peek.pause_with_reason(pause, "empty array");
+            lengths.set(peek.stream_id(), ArrayLength::Elements(0));
+        }
+    })?;
+
+    // Catch `[, 1]` explicitly, as the generic error from parsing the element would be confusing.
+    state.peek_token(|peek| {
+        if peek.peek_kind() == Some(TokenKind::Comma) {
+            peek.fail("unexpected comma at start of array");
         }
     })?;
 
     // TODO: add comment about unrolling the 1st element.
-    parse_expression(state)?;
+    parse_array_element(state, lengths)?;
 
     diverge!(match state {
         Token::Semicolon => |state| {
+            // The length of `[x; n]` depends on the value of `n`, not on the syntax.
+            for id in state.unpaused_ids() {
+                lengths.set(id, ArrayLength::Repeat);
+            }
+
             state.expect(Token::Semicolon)?;
             parse_expression(state)?;
             state.expect(Token::CloseSquare)?;
@@ -72,31 +338,21 @@ pub fn parse_array(state: &mut State<'_>) -> Result<(), Error> {
         },
         Token::CloseSquare => |state| state.expect(Token::CloseSquare),
         _ => |state| {
-            // Comma after the first expression
-            state.expect(Token::Comma)?;
+            // Comma after the first expression. Mention all the tokens that could follow the first
+            // element, as a missing `;` in a repeat (like `[x n]`) ends up here too.
+            state.next_token(|next| {
+                if next.token != Token::Comma {
+                    next.mismatch("comma, semicolon or end of array");
+                }
+            })?;
 
             // Parse zero or more array items:
-            while_any_unpaused(state, |state, pause| {
-                // Handles the closing ] either when the array is empty, or when there is a trailing comma.
-                state.peek_token(|peek| {
-                    if let Some(Token::CloseSquare) = &peek.token {
-                        peek.consume();
-                        peek.pause(pause);
-                    }
-                })?;
-
-                parse_expression(state)?;
-
-                state.next_token(|next| match &next.token {
-                    Token::CloseSquare => next.pause(pause),
-                    Token::Comma => {}
-                    _ => next.mismatch("end of array or comma"),
-                })?;
-                Ok(())
+            parse_separated(state, "end of array", Token::CloseSquare, |state| {
+                parse_array_element(state, lengths)
             })?;
 
             Ok(())
-        }
+        },
     });
 
     state.unpause(pause);
@@ -104,10 +360,112 @@ pub fn parse_array(state: &mut State<'_>) -> Result<(), Error> {
     Ok(())
 }
 
+/// Parse a single array element, counting it in the array lengths.
+///
+/// If [`ParseConfig::recover_array_elements`] is enabled, streams failing to parse the element
+/// are skipped to the next `,` or `]`, and the error is recorded in the state rather than
+/// aborting the parse.
+fn parse_array_element(state: &mut State<'_>, lengths: &mut ArrayLengths) -> Result<(), Error> {
+    // Note which array element is about to be parsed, to include it in errors.
+    state.peek_token(|peek| {
+        let id = peek.stream_id();
+        let element = match lengths.get(id) {
+            Some(ArrayLength::Elements(count) | ArrayLength::AtLeast(count)) => count + 1,
+            _ => {
+                lengths.set(id, ArrayLength::Elements(0));
+                1
+            }
+        };
+        peek.note(&format!("while parsing array element {element}"));
+    })?;
+
+    // A spread element (`..xs`) contains an unknown number of elements, so it's not counted.
+    let mut spreads = Vec::new();
+    state.peek_token(|peek| {
+        if let Some(Token::DotDot) = &peek.token {
+            let id = peek.stream_id();
+            match lengths.get(id) {
+                Some(ArrayLength::Elements(count)) => {
+                    lengths.set(id, ArrayLength::AtLeast(count));
+                    spreads.push(id);
+                }
+                _ => peek.fail("only one spread is allowed per array"),
+            }
+            peek.consume();
+        }
+    })?;
+
+    let recovery = if state.config.recover_array_elements {
+        Some(state.new_pause_id())
+    } else {
+        None
+    };
+    // Keep any recovery set by the caller (like State::retire_failed) if not recovering here.
+    let previous_recovery = state.recovery;
+    state.recovery = recovery.or(previous_recovery);
+    let result = parse_expression(state);
+    state.recovery = previous_recovery;
+    result?;
+
+    for id in state.unpaused_ids() {
+        if !spreads.contains(&id) {
+            lengths.increment(id);
+        }
+    }
+
+    if let Some(recovery) = recovery {
+        state.skip_recovered(recovery, |token| {
+            matches!(token, Token::Comma | Token::CloseSquare)
+        })?;
+    }
+    state.pop_note();
+
+    Ok(())
+}
+
+/// Number of elements of the arrays parsed by [`parse_array_with_lengths`], for each stream.
+#[derive(Debug, Default)]
+pub struct ArrayLengths {
+    lengths: BTreeMap<StreamId, ArrayLength>,
+}
+
+impl ArrayLengths {
+    /// Return the length of the array parsed in the provided stream, if the stream contained one.
+    pub fn get(&self, id: StreamId) -> Option<ArrayLength> {
+        self.lengths.get(&id).copied()
+    }
+
+    fn set(&mut self, id: StreamId, length: ArrayLength) {
+        self.lengths.insert(id, length);
+    }
+
+    fn increment(&mut self, id: StreamId) {
+        if let Some(ArrayLength::Elements(count) | ArrayLength::AtLeast(count)) =
+            self.lengths.get_mut(&id)
+        {
+            *count += 1;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArrayLength {
+    /// The array lists its elements explicitly, and this is how many there are.
+    Elements(usize),
+    /// The array uses the `[x; n]` form, whose length is only known once `n` is evaluated.
+    Repeat,
+    /// The array contains a spread element (like `[1, ..xs]`), and this is how many other
+    /// elements there are.
+    AtLeast(usize),
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::streams::Streams;
+    use insta::assert_debug_snapshot;
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     #[test]
     fn test_parse_expression() {
@@ -121,6 +479,215 @@ mod test {
         .unwrap();
     }
 
+    #[test]
+    fn test_parse_tuples_and_maps() {
+        let inputs = [
+            "(1, 2)",
+            "(1, (2, 3), [4]) + 5",
+            "{}",
+            "{1: 2, \"a\": [3]}",
+            "{1: {2: 3}}",
+            "{a ? b : c: d ? e : f}",
+        ];
+        let mut streams = Streams::new();
+        let ids = inputs.map(|input| streams.add(input));
+        let mut parallel = State::new(streams);
+        parse_expression(&mut parallel).unwrap();
+        assert!(ids.iter().all(|id| parallel.stream(*id).is_finished()));
+
+        let error = |input| {
+            let err = parse_expression(&mut state(&[input])).unwrap_err();
+            err.root_cause().to_string()
+        };
+        assert_eq!(
+            "expected end of expression or comma, found Token( 2 )",
+            error("(1 2)")
+        );
+        assert_eq!("expected Token( : ), found Token( 2 )", error("{1 2}"));
+        assert_eq!(
+            "expected end of map or comma, found Token( 3 )",
+            error("{1: 2 3}")
+        );
+        assert_eq!("expected expression, found Token( , )", error("(,)"));
+    }
+
+    #[test]
+    fn test_clone_state() {
+        let mut streams = Streams::new();
+        let plus = streams.add("1 + 2");
+        let minus = streams.add("1 - 2");
+        let mut original = State::new(streams);
+        original.expect(Token::Number(1)).unwrap();
+
+        let mut copy = original.clone();
+        let pause = original.new_pause_id();
+        for stream in original.streams.iter_mut() {
+            if stream.id() == minus {
+                stream.pause(pause);
+            }
+        }
+        original.expect(Token::Plus).unwrap();
+        original.expect(Token::Number(2)).unwrap();
+        assert!(original.stream(plus).is_finished());
+        assert_eq!(vec![plus], original.unpaused_ids());
+
+        // The copy is still where the original was when it was cloned.
+        assert_eq!(vec![plus, minus], copy.unpaused_ids());
+        assert_eq!(Some(2), copy.stream(plus).tokens_left());
+        let err = copy.expect(Token::Plus).unwrap_err();
+        assert_eq!("expected Token( + ), found Token( - )", err.to_string());
+    }
+
+    #[test]
+    fn test_clone_state_errors() {
+        let config = ParseConfig::new().recover_array_elements(true);
+        let mut state = state_with_config(&["[1, @]"], config);
+        parse_array(&mut state).unwrap();
+
+        let copy = state.clone();
+        let messages = |state: &State<'_>| {
+            let (id, err) = &state.recovered_errors()[0];
+            (*id, format!("{err:#}"))
+        };
+        assert_eq!(messages(&state), messages(&copy));
+        assert_eq!(
+            "while parsing array element 2: expected expression, found Token( @ )",
+            messages(&copy).1
+        );
+    }
+
+    #[test]
+    fn test_drain_completed() {
+        let mut streams = Streams::new();
+        let short = streams.add("1");
+        let long = streams.add("1 + 2");
+        let mut state = State::new(streams);
+        assert!(state.drain_completed().is_empty());
+
+        state.expect(Token::Number(1)).unwrap();
+        assert_eq!(vec![short], state.drain_completed());
+        assert_eq!(1, state.streams().len());
+        assert_eq!(Some(2), state.stream(long).tokens_left());
+
+        // IDs of removed streams are not reused.
+        let spawned = state.spawn_stream("+ 3");
+        assert!(spawned != short && spawned != long);
+
+        state.expect(Token::Plus).unwrap();
+        state
+            .expect_pred(|t| t.as_number().is_some(), "number")
+            .unwrap();
+        assert_eq!(vec![long, spawned], state.drain_completed());
+        assert!(state.streams().is_empty());
+    }
+
+    #[test]
+    fn test_no_streams() {
+        // Without streams there is nothing to parse, which is not an error.
+        parse_expression(&mut state(&[])).unwrap();
+        parse_array(&mut state(&[])).unwrap();
+        let lengths = parse_array_with_lengths(&mut state(&[])).unwrap();
+        assert!(lengths.lengths.is_empty());
+
+        let mut empty = state(&[]);
+        let results = empty.retire_failed(parse_expression).unwrap();
+        assert!(results.completed.is_empty() && results.failed.is_empty());
+        assert_eq!(None, empty.best_completed());
+        assert_eq!(Vec::<usize>::new(), matching_grammars("1", &[]).unwrap());
+    }
+
+    #[test]
+    fn test_trailing_operator() {
+        let error = |input| {
+            let err = parse_expression(&mut state(&["1", input])).unwrap_err();
+            err.root_cause().to_string()
+        };
+        assert_eq!(
+            "expected expression after binary operator `+`",
+            error("1 +")
+        );
+        assert_eq!(
+            "expected expression after binary operator `-`",
+            error("[1 -")
+        );
+        assert_eq!(
+            "expected expression after binary operator `**`",
+            error("2 **")
+        );
+        assert_eq!("expected expression after `?`", error("a ?"));
+        assert_eq!("end of input", error("a ? 1"));
+    }
+
+    #[test]
+    fn test_parse_expression_unary() {
+        let mut streams = Streams::new();
+        let ids = ["1 - -2", "--1", "1 -- 2", "-[1][0] - -(2)", "[-1, - - 2]"]
+            .map(|program| streams.add(program));
+        let mut unary = State::new(streams);
+        parse_expression(&mut unary).unwrap();
+        assert!(ids.iter().all(|id| unary.stream(*id).is_finished()));
+        assert_eq!(
+            vec![(ids[1], 1), (ids[4], 5)],
+            unary
+                .take_diagnostics()
+                .into_iter()
+                .map(|diagnostic| (diagnostic.stream, diagnostic.position))
+                .collect::<Vec<_>>()
+        );
+
+        let err = parse_expression(&mut state(&["1 - -"])).unwrap_err();
+        assert_eq!(
+            "expected expression after unary operator `-`",
+            err.to_string()
+        );
+        let err = parse_expression(&mut state(&["1 + +2"])).unwrap_err();
+        assert_eq!("expected expression, found Token( + )", err.to_string());
+    }
+
+    #[test]
+    fn test_parse_expression_identifiers() {
+        parse_expression(&mut state(&[
+            // Parsed in parallel:
+            "x",
+            "foo + 1",
+            "a + b - c",
+        ]))
+        .unwrap();
+
+        let config = ParseConfig::new().allow_identifiers(false);
+        let err = parse_expression(&mut state_with_config(&["x"], config)).unwrap_err();
+        assert_eq!("expected expression, found Token( x )", err.to_string());
+    }
+
+    #[test]
+    fn test_primary_hook() {
+        // Accept `nil` even when identifiers are not, and `@` followed by any token.
+        let config = ParseConfig::new()
+            .allow_identifiers(false)
+            .primary_hook(|input| match input.peek(0).as_deref() {
+                Some("nil") => 1,
+                Some("@") => 2,
+                _ => 0,
+            });
+        parse_expression(&mut state_with_config(
+            &["nil + 1", "[1, nil, @x]", "1 + @\"a\"[0]", "(@@)"],
+            config.clone(),
+        ))
+        .unwrap();
+
+        let err = parse_expression(&mut state_with_config(&["x"], config.clone())).unwrap_err();
+        assert_eq!("expected expression, found Token( x )", err.to_string());
+        let err = parse_expression(&mut state_with_config(&["1 + @"], config)).unwrap_err();
+        assert_eq!(
+            "custom primary is longer than the rest of the input",
+            err.to_string()
+        );
+
+        // Setting a hook keeps the configuration shareable between threads.
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+        assert_send_sync(&ParseConfig::new().primary_hook(|_| 0));
+    }
+
     #[test]
     fn test_parse_array() {
         parse_array(&mut state(&[
@@ -135,11 +702,724 @@ mod test {
         .unwrap();
     }
 
-    fn state(inputs: &[&'static str]) -> State<'static> {
+    #[test]
+    fn test_nested_repeat() {
+        let mut streams = Streams::new();
+        let nested = streams.add("[[42; 101]; 69]");
+        let inner = streams.add("[[1; 2], [3; 4]]");
+        let lengths = parse_array_with_lengths(&mut State::new(streams)).unwrap();
+        assert_eq!(Some(ArrayLength::Repeat), lengths.get(nested));
+        assert_eq!(Some(ArrayLength::Elements(2)), lengths.get(inner));
+
+        let err = parse_array(&mut state(&["[[42 101]; 69]"])).unwrap_err();
+        let chain = err.chain().map(|c| c.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "while parsing array element 1",
+                "expected comma, semicolon or end of array, found Token( 101 )",
+            ],
+            chain
+        );
+
+        let err = parse_array(&mut state(&["[[42; 101] 69]"])).unwrap_err();
+        assert_eq!(
+            "expected comma, semicolon or end of array, found Token( 69 )",
+            err.to_string()
+        );
+
+        let err = parse_array(&mut state(&["[[42; 101; 69]]"])).unwrap_err();
+        assert_eq!(
+            "expected Token( ] ), found Token( ; )",
+            err.root_cause().to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_array_with_lengths() {
+        let mut streams = Streams::new();
+        let three = streams.add("[1, 2, 3]");
+        let trailing = streams.add("[1, [2, 3],]");
+        let one = streams.add("[1 + 2]");
+        let empty = streams.add("[]");
+        let repeat = streams.add("[1; 3]");
+
+        let lengths = parse_array_with_lengths(&mut State::new(streams)).unwrap();
+        assert_eq!(Some(ArrayLength::Elements(3)), lengths.get(three));
+        assert_eq!(Some(ArrayLength::Elements(2)), lengths.get(trailing));
+        assert_eq!(Some(ArrayLength::Elements(1)), lengths.get(one));
+        assert_eq!(Some(ArrayLength::Elements(0)), lengths.get(empty));
+        assert_eq!(Some(ArrayLength::Repeat), lengths.get(repeat));
+    }
+
+    #[test]
+    fn test_parse_array_error_notes() {
+        let err = parse_array(&mut state(&["[1, 2]", "[1, [2], [3, ;]]"])).unwrap_err();
+
+        let chain = err.chain().map(|c| c.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            &[
+                "while parsing array element 3",
+                "while parsing array element 2",
+                "expected expression, found Token( ; )",
+            ],
+            chain.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config = ParseConfig::new().allow_trailing_comma(false);
+        parse_array(&mut state_with_config(&["[1, 2]", "[]"], config.clone())).unwrap();
+        let err = parse_array(&mut state_with_config(&["[1, 2,]"], config)).unwrap_err();
+        assert_eq!(
+            "trailing comma not allowed before `]`, at token 5",
+            err.root_cause().to_string()
+        );
+
+        let config = ParseConfig::new().max_iterations(Some(2));
+        parse_expression(&mut state_with_config(&["1 + 2"], config.clone())).unwrap();
+        let err = parse_expression(&mut state_with_config(&["1 + 2 + 3"], config)).unwrap_err();
+        assert_eq!("exceeded the maximum of 2 iterations", err.to_string());
+    }
+
+    #[test]
+    fn test_trailing_comma_policy() {
+        // All the comma separated lists follow the same policy.
+        let inputs = [
+            ("[1, 2]", "[1, 2,]"),
+            ("(1, 2)", "(1, 2,)"),
+            ("{1: 2, 3: 4}", "{1: 2, 3: 4,}"),
+            ("f(1, 2)", "f(1, 2,)"),
+        ];
+        for allow in [true, false] {
+            let config = ParseConfig::new().allow_trailing_comma(allow);
+            for (without, with) in inputs {
+                parse_expression(&mut state_with_config(&[without], config.clone())).unwrap();
+                let result = parse_expression(&mut state_with_config(&[with], config.clone()));
+                assert_eq!(
+                    allow,
+                    result.is_ok(),
+                    "{with} with allow_trailing_comma({allow})"
+                );
+            }
+        }
+
+        let config = ParseConfig::new().allow_trailing_comma(false);
+        let error = |input| {
+            let mut state = state_with_config(&[input], config.clone());
+            parse_expression(&mut state)
+                .unwrap_err()
+                .root_cause()
+                .to_string()
+        };
+        assert_eq!(
+            "trailing comma not allowed before `)`, at token 5",
+            error("(1, 2,)")
+        );
+        assert_eq!(
+            "trailing comma not allowed before `}`, at token 5",
+            error("{1: 2,}")
+        );
+    }
+
+    #[test]
+    fn test_expect_pred() {
+        let mut numbers = state(&["42", "1"]);
+        numbers
+            .expect_pred(|t| t.as_number().is_some(), "number")
+            .unwrap();
+
+        let mut strings = state(&["\"hello\""]);
+        let err = strings
+            .expect_pred(|t| t.as_number().is_some(), "number")
+            .unwrap_err();
+        assert_eq!("expected number, found Token( \"hello\" )", err.to_string());
+    }
+
+    #[test]
+    fn test_diverge_trace() {
+        let mut streams = Streams::new();
+        let number = streams.add("1");
+        let nested = streams.add("(2)");
+        let mut state = State::with_config(streams, ParseConfig::new().trace_diverge(true));
+        parse_expression(&mut state).unwrap();
+
+        assert_eq!(
+            &[
+                (nested, "Token::OpenParen".to_string()),
+                (nested, "_".to_string()),
+                (number, "_".to_string()),
+            ],
+            state.diverge_trace()
+        );
+    }
+
+    #[test]
+    fn test_streams_snapshot() {
+        let mut state = state(&["[1]", "[2, 3]"]);
+        state.expect(Token::OpenSquare).unwrap();
+        parse_expression(&mut state).unwrap();
+        assert_debug_snapshot!(state.streams().snapshot(), @r###"
+        StreamsSnapshot {
+            streams: [
+                StreamSnapshot {
+                    source: "[1]",
+                    position: 2,
+                    paused_by: [],
+                    pause_reasons: [],
+                },
+                StreamSnapshot {
+                    source: "[2, 3]",
+                    position: 2,
+                    paused_by: [],
+                    pause_reasons: [],
+                },
+            ],
+        }
+        "###);
+
+        let pause = state.new_pause_id();
+        state
+            .next_token(|next| {
+                if next.token == Token::CloseSquare {
+                    next.pause_with_reason(pause, "end of array");
+                }
+            })
+            .unwrap();
+        assert_debug_snapshot!(state.streams().snapshot(), @r###"
+        StreamsSnapshot {
+            streams: [
+                StreamSnapshot {
+                    source: "[1]",
+                    position: 3,
+                    paused_by: [
+                        0,
+                    ],
+                    pause_reasons: [
+                        Some(
+                            "end of array",
+                        ),
+                    ],
+                },
+                StreamSnapshot {
+                    source: "[2, 3]",
+                    position: 3,
+                    paused_by: [],
+                    pause_reasons: [],
+                },
+            ],
+        }
+        "###);
+    }
+
+    #[test]
+    fn test_parse_array_recovery() {
+        let mut streams = Streams::new();
+        let invalid = streams.add("[1, @, 3]");
+        let valid = streams.add("[4, 5]");
+        let config = ParseConfig::new().recover_array_elements(true);
+        let mut state = State::with_config(streams, config);
+
+        let lengths = parse_array_with_lengths(&mut state).unwrap();
+        assert_eq!(Some(ArrayLength::Elements(2)), lengths.get(invalid));
+        assert_eq!(Some(ArrayLength::Elements(2)), lengths.get(valid));
+
+        let errors = state.recovered_errors();
+        assert_eq!(1, errors.len());
+        assert_eq!(invalid, errors[0].0);
+        assert_eq!(
+            "expected expression, found Token( @ )",
+            errors[0].1.root_cause().to_string()
+        );
+    }
+
+    #[test]
+    fn test_stream_handle() {
+        let mut streams = Streams::new();
+        let recovered = streams.add("[1, @]");
+        let valid = streams.add("[2]");
+        let trailing = streams.add("[3] 4");
+        let unlexable = streams.add("[5] \"6");
+        let retired = streams.add("[1 2]");
+        let config = ParseConfig::new().recover_array_elements(true);
+        let mut state = State::with_config(streams, config);
+        let results = state.retire_failed(parse_array).unwrap();
+        assert_eq!(
+            vec![retired],
+            results.failed.into_keys().collect::<Vec<_>>()
+        );
+
+        let recovered = state.stream(recovered);
+        assert!(!recovered.is_success());
+        assert_eq!(1, recovered.errors().count());
+        assert!(recovered.is_finished());
+
+        let valid = state.stream(valid);
+        assert!(valid.is_success());
+        assert_eq!(3, valid.position());
+
+        let trailing = state.stream(trailing);
+        assert!(!trailing.is_success());
+        assert_eq!(Some(1), trailing.tokens_left());
+        assert!(!trailing.is_finished());
+
+        let unlexable = state.stream(unlexable);
+        assert!(!unlexable.is_success());
+        assert_eq!(None, unlexable.tokens_left());
+
+        // Errors that retired a stream are not recovered from, but the stream still failed.
+        let retired = state.stream(retired);
+        assert!(!retired.is_success());
+        assert_eq!(0, retired.errors().count());
+        assert_eq!(Some(1), retired.tokens_left());
+    }
+
+    #[test]
+    fn test_parse_expression_until() {
+        let mut streams = Streams::new();
+        let paren = streams.add("1 + 2 )");
+        let minus = streams.add("3 + 4 - 5");
+        let mut state = State::new(streams);
+        parse_expression_until(&mut state, &[Token::CloseParen, Token::Dash]).unwrap();
+
+        assert_eq!(Some(1), state.stream(paren).tokens_left());
+        assert_eq!(Some(2), state.stream(minus).tokens_left());
+    }
+
+    #[test]
+    fn test_expect_reporting() {
+        let mut state = state(&["1", "1 1"]);
+        let pause = state.new_pause_id();
+        state.streams.iter_mut().next().unwrap().pause(pause);
+        assert_eq!(1, state.expect_reporting(Token::Number(1)).unwrap());
+
+        state.unpause(pause);
+        assert_eq!(2, state.expect_reporting(Token::Number(1)).unwrap());
+    }
+
+    #[test]
+    fn test_concatenate_strings() {
+        let parse = |config| {
+            let mut streams = Streams::new();
+            let concatenated = streams.add("\"a\" \"b\"");
+            let single = streams.add("\"x\"");
+            let mut state = State::with_config(streams, config);
+            parse_expression(&mut state).unwrap();
+            (
+                state.stream(concatenated).tokens_left(),
+                state.stream(single).tokens_left(),
+            )
+        };
+
+        assert_eq!((Some(1), Some(0)), parse(ParseConfig::new()));
+        assert_eq!(
+            (Some(0), Some(0)),
+            parse(ParseConfig::new().concatenate_strings(true))
+        );
+    }
+
+    #[test]
+    fn test_power() {
+        let mut streams = Streams::new();
+        let power = streams.add("2 ** 3 ** 2");
+        let mixed = streams.add("1 + 2 ** 3 - 4");
+        let plain = streams.add("5 - 6");
+        let mut state = State::new(streams);
+        parse_expression(&mut state).unwrap();
+
+        for id in [power, mixed, plain] {
+            assert!(state.stream(id).is_finished());
+        }
+    }
+
+    #[test]
+    fn test_assert_synced() {
+        let mut synced = state(&["[1] + 2", "[3, 4] + 5"]);
+        parse_array(&mut synced).unwrap();
+        synced.assert_synced().unwrap();
+
+        let mut desynced = state(&["[1] + 2", "[3, 4] 5"]);
+        parse_array(&mut desynced).unwrap();
+        let err = desynced.assert_synced().unwrap_err();
+        assert_eq!(
+            "streams are not synced: StreamId(0) is at Some(Token( + )), \
+             while StreamId(1) is at Some(Token( 5 ))",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_spread() {
+        let mut streams = Streams::new();
+        let spread = streams.add("[1, ..xs, 2]");
+        let first = streams.add("[..[3, 4]]");
+        let plain = streams.add("[5, 6]");
+        let mut state = State::new(streams);
+        let lengths = parse_array_with_lengths(&mut state).unwrap();
+
+        assert_eq!(Some(ArrayLength::AtLeast(2)), lengths.get(spread));
+        assert_eq!(Some(ArrayLength::AtLeast(0)), lengths.get(first));
+        assert_eq!(Some(ArrayLength::Elements(2)), lengths.get(plain));
+    }
+
+    #[test]
+    fn test_multiple_spreads() {
+        let mut state = state(&["[..a, ..b]"]);
+        let err = parse_array(&mut state).unwrap_err();
+        assert_eq!(
+            "only one spread is allowed per array, at token 4",
+            err.root_cause().to_string()
+        );
+    }
+
+    #[test]
+    fn test_retire_failed() {
+        let mut streams = Streams::new();
+        let number = streams.add("1");
+        let plus = streams.add("+");
+        let square = streams.add("[");
+        let mut state = State::new(streams);
+        let results = state.retire_failed(parse_expression).unwrap();
+
+        assert_eq!(vec![number], results.completed);
+        let failed = results
+            .failed
+            .iter()
+            .map(|(id, err)| (*id, err.root_cause().to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (plus, "expected expression, found Token( + )".to_string()),
+                (square, "end of input".to_string()),
+            ],
+            failed
+        );
+    }
+
+    #[test]
+    fn test_retire_failed_recovered() {
+        let config = ParseConfig::new().recover_array_elements(true);
+        let mut streams = Streams::new();
+        let recovered = streams.add("[1, @]");
+        let retired = streams.add("[@, +, 1");
+        let mut state = State::with_config(streams, config);
+        let results = state.retire_failed(parse_array).unwrap();
+
+        assert_eq!(vec![recovered], results.completed);
+        let failed = results
+            .failed
+            .iter()
+            .map(|(id, err)| (*id, err.root_cause().to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(retired, "end of input".to_string())], failed);
+
+        // Errors the streams recovered from are not considered failures.
+        let recovered_errors = state
+            .recovered_errors()
+            .iter()
+            .map(|(id, err)| (*id, err.root_cause().to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (retired, "expected expression, found Token( @ )".to_string()),
+                (
+                    recovered,
+                    "expected expression, found Token( @ )".to_string()
+                ),
+                (retired, "expected expression, found Token( + )".to_string()),
+            ],
+            recovered_errors
+        );
+    }
+
+    #[test]
+    fn test_expect_keyword() {
+        state(&["if"]).expect_keyword("if").unwrap();
+
+        let err = state(&["\"if\""]).expect_keyword("if").unwrap_err();
+        assert_eq!(
+            "expected keyword `if`, found Token( \"if\" )",
+            err.to_string()
+        );
+        let err = state(&["iff"]).expect_keyword("if").unwrap_err();
+        assert_eq!("expected keyword `if`, found Token( iff )", err.to_string());
+    }
+
+    #[test]
+    fn test_best_completed() {
+        let mut streams = Streams::new();
+        let first = streams.add("[1]");
+        let preferred = streams.add("[2]");
+        let invalid = streams.add("[+]");
+        let same = streams.add("[3]");
+        streams.set_priority(preferred, 10);
+        streams.set_priority(invalid, 20);
+        streams.set_priority(same, 10);
+
+        let mut state = State::new(streams);
+        assert_eq!(None, state.best_completed());
+        let results = state.retire_failed(parse_array).unwrap();
+        assert_eq!(vec![first, preferred, same], results.completed);
+        assert_eq!(Some(preferred), state.best_completed());
+    }
+
+    #[test]
+    fn test_index() {
+        let mut streams = Streams::new();
+        let ids = [
+            streams.add("x[0]"),
+            streams.add("[1][0]"),
+            streams.add("a[0][1 + i] + b[[2][0]]"),
+            streams.add("[1, 2]"),
+        ];
+        let mut state = State::new(streams);
+        parse_expression(&mut state).unwrap();
+        for id in ids {
+            assert!(state.stream(id).is_finished());
+        }
+    }
+
+    #[test]
+    fn test_index_unterminated() {
+        let err = parse_expression(&mut state(&["x[0"])).unwrap_err();
+        assert_eq!("end of input", err.to_string());
+    }
+
+    #[test]
+    fn test_peek_consensus() {
+        let mut operators = state(&["1 + 2", "1 - 3"]);
+        assert_eq!(Some(Token::Number(1)), operators.peek_consensus());
+        operators.expect(Token::Number(1)).unwrap();
+        assert_eq!(None, operators.peek_consensus());
+
+        assert_eq!(None, state(&["", ""]).peek_consensus());
+    }
+
+    #[test]
+    fn test_peek_kind() {
+        let mut streams = Streams::new();
+        let one = streams.add("1");
+        let string = streams.add("\"a\"");
+        let two = streams.add("2");
+        let empty = streams.add("");
+        let mut state = State::new(streams);
+
+        // Group the streams by the kind of their next token.
+        let mut groups = BTreeMap::new();
+        state
+            .peek_token(|peek| {
+                let id = peek.stream_id();
+                groups
+                    .entry(peek.peek_kind())
+                    .or_insert_with(Vec::new)
+                    .push(id);
+            })
+            .unwrap();
+        assert_eq!(
+            vec![
+                (None, vec![empty]),
+                (Some(TokenKind::Number), vec![one, two]),
+                (Some(TokenKind::String), vec![string]),
+            ],
+            groups.into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_matching_grammars() {
+        let grammars: &[Grammar] = &[parse_array, parse_expression];
+        assert_eq!(vec![0, 1], matching_grammars("[1]", grammars).unwrap());
+        assert_eq!(vec![1], matching_grammars("(1)", grammars).unwrap());
+        assert_eq!(vec![0], matching_grammars("[1]", &[parse_array]).unwrap());
+        assert!(matching_grammars("[1", grammars).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ternary() {
+        let mut state = state(&[
+            "1 ? 2 : 3",
+            "1 + 2 ? [3] : 4 ** 5",
+            "1 ? 2 ? 3 : 4 : 5",
+            "1 ? 2 : 3 ? 4 : 5",
+        ]);
+        parse_expression(&mut state).unwrap();
+        assert!(state.streams.iter().all(|s| s.tokens_left() == Some(0)));
+    }
+
+    #[test]
+    fn test_ternary_missing_else() {
+        let err = parse_expression(&mut state(&["1 ? 2"])).unwrap_err();
+        assert_eq!("end of input", err.to_string());
+
+        let mut stopped = state(&["[1 ? 2 : 3, 4]"]);
+        parse_array(&mut stopped).unwrap();
+        assert!(stopped.streams.iter().all(|s| s.tokens_left() == Some(0)));
+    }
+
+    #[test]
+    fn test_peek_nth() {
+        let mut addition = state(&["1 + 2"]);
+        assert_eq!(Some(Token::Number(2)), addition.peek_nth(2));
+        assert_eq!(None, addition.peek_nth(3));
+        // Looking ahead doesn't consume any token.
+        addition.expect(Token::Number(1)).unwrap();
+        assert_eq!(Some(Token::Number(2)), addition.peek_nth(1));
+
+        assert_eq!(None, state(&["1 + 2", "1 - 2"]).peek_nth(1));
+        assert_eq!(
+            Some(Token::Number(2)),
+            state(&["1 + 2", "1 - 2"]).peek_nth(2)
+        );
+    }
+
+    #[test]
+    fn test_from_programs() {
+        let mut state = State::from_programs(["[1]", "[2, 3]"]);
+        assert_eq!(2, state.streams().len());
+        parse_array(&mut state).unwrap();
+        assert!(state.streams.iter().all(|s| s.tokens_left() == Some(0)));
+    }
+
+    #[test]
+    fn test_pause_ids_allocated() {
+        let mut number = state(&["1"]);
+        assert_eq!(0, number.pause_ids_allocated());
+        parse_expression(&mut number).unwrap();
+        // One for the expression loop, one for the number branch and one for the postfix loop.
+        assert_eq!(3, number.pause_ids_allocated());
+
+        let mut array = state(&["[1, 2]"]);
+        parse_expression(&mut array).unwrap();
+        // Each element of the array is a nested expression, allocating its own IDs.
+        assert_eq!(12, array.pause_ids_allocated());
+    }
+
+    #[test]
+    fn test_call() {
+        let mut calls = state(&["f()", "g(1, 2)", "h(1,)", "f(g(1))(2)[0]", "[f(1), (2)]"]);
+        parse_expression(&mut calls).unwrap();
+        assert!(calls.streams.iter().all(|s| s.tokens_left() == Some(0)));
+    }
+
+    #[test]
+    fn test_call_errors() {
+        let err = parse_expression(&mut state(&["f(1 2)"])).unwrap_err();
+        assert_eq!(
+            "expected end of arguments or comma, found Token( 2 )",
+            err.root_cause().to_string()
+        );
+
+        let config = ParseConfig::new().allow_trailing_comma(false);
+        let err = parse_expression(&mut state_with_config(&["h(1,)"], config.clone())).unwrap_err();
+        assert_eq!(
+            "trailing comma not allowed before `)`, at token 4",
+            err.root_cause().to_string()
+        );
+        let err = parse_expression(&mut state_with_config(&["f(g(1, 2,))"], config)).unwrap_err();
+        assert_eq!(
+            "trailing comma not allowed before `)`, at token 8",
+            err.root_cause().to_string()
+        );
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        // Grammar accepting any number, but warning about zeroes.
+        fn parse_number(state: &mut State<'_>) -> Result<(), Error> {
+            state.next_token(|next| match next.token {
+                Token::Number(0) => next.warn("zero is not a useful number"),
+                Token::Number(_) => {}
+                _ => next.mismatch("number"),
+            })
+        }
+
+        let mut streams = Streams::new();
+        let first = streams.add("0 1");
+        let second = streams.add("1 0");
+        let mut state = State::new(streams);
+
+        parse_number(&mut state).unwrap();
+        parse_number(&mut state).unwrap();
+        let message = "zero is not a useful number".to_string();
+        assert_eq!(
+            vec![
+                Diagnostic {
+                    stream: first,
+                    position: 1,
+                    message: message.clone()
+                },
+                Diagnostic {
+                    stream: second,
+                    position: 2,
+                    message
+                },
+            ],
+            state.take_diagnostics()
+        );
+        assert!(state.take_diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_leading_comma() {
+        let mut state = state(&["[1]", "[, 1]"]);
+        let err = parse_array(&mut state).unwrap_err();
+        assert_eq!(
+            "unexpected comma at start of array, at token 1",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_token_hook() {
+        let mut streams = Streams::new();
+        let first = streams.add("1 + 2");
+        let second = streams.add("3");
+        let mut state = State::new(streams);
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_hook = events.clone();
+        state.set_token_hook(move |event| {
+            let token = event.token.map(|t| format!("{t:?}"));
+            events_hook
+                .borrow_mut()
+                .push((event.stream, token, event.consumed));
+        });
+        parse_expression(&mut state).unwrap();
+
+        let token = |t: &str| Some(t.to_string());
+        assert_eq!(
+            vec![
+                // Checking for unary operators:
+                (first, token("Token( 1 )"), false),
+                (second, token("Token( 3 )"), false),
+                (first, token("Token( 1 )"), false),
+                (second, token("Token( 3 )"), false),
+                (first, token("Token( 1 )"), true),
+                (second, token("Token( 3 )"), true),
+                // Checking whether the values are indexed:
+                (first, token("Token( + )"), false),
+                (second, None, false),
+                (first, token("Token( + )"), true),
+                (second, None, false),
+                (first, token("Token( 2 )"), false),
+                (first, token("Token( 2 )"), false),
+                (first, token("Token( 2 )"), true),
+                (first, None, false),
+                (first, None, false),
+            ],
+            *events.borrow()
+        );
+    }
+
+    fn state_with_config(inputs: &[&'static str], config: ParseConfig) -> State<'static> {
         let mut streams = Streams::new();
         for input in inputs {
             streams.add(input);
         }
-        State::new(streams)
+        State::with_config(streams, config)
+    }
+
+    fn state(inputs: &[&'static str]) -> State<'static> {
+        state_with_config(inputs, ParseConfig::default())
     }
 }
@@ -1,22 +1,311 @@
-use crate::lexer::Token;
-use crate::streams::{PauseId, Stream, StreamId, Streams};
-use anyhow::{anyhow, Error};
-use std::fmt::Debug;
+use crate::lexer::{Token, TokenKind};
+use crate::parser::config::{ParseConfig, PrimaryHook};
+use crate::streams::{PauseId, PauseIds, Stream, StreamId, Streams};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use anyhow::{anyhow, bail, Error};
+use core::cmp::Reverse;
+use core::fmt::Debug;
+
+type TokenHook = Box<dyn FnMut(&TokenEvent<'_>)>;
 
 pub struct State<'src> {
     pub(super) streams: Streams<'src>,
+    pub(super) config: ParseConfig,
+    pub(super) diverge_trace: Vec<(StreamId, String)>,
+    /// When set, streams failing to parse are paused with this [`PauseId`] and their errors are
+    /// recorded, instead of aborting the whole parse.
+    pub(super) recovery: Option<PauseId>,
+    recovered_errors: Vec<(StreamId, Error)>,
+    token_hook: Option<TokenHook>,
+    pause_ids: PauseIds,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl<'src> State<'src> {
     pub fn new(streams: Streams<'src>) -> Self {
-        Self { streams }
+        Self::with_config(streams, ParseConfig::default())
+    }
+
+    /// Create a state parsing each of the provided programs in its own stream, in order. This
+    /// is a shorthand for adding them to [`Streams`] and then calling [`State::new`].
+    pub fn from_programs<I>(programs: I) -> Self
+    where
+        I: IntoIterator<Item = &'src str>,
+    {
+        let mut streams = Streams::new();
+        for program in programs {
+            streams.add(program);
+        }
+        Self::new(streams)
+    }
+
+    pub fn with_config(streams: Streams<'src>, config: ParseConfig) -> Self {
+        Self {
+            streams,
+            config,
+            diverge_trace: Vec::new(),
+            recovery: None,
+            recovered_errors: Vec::new(),
+            token_hook: None,
+            pause_ids: PauseIds::default(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// Invoke the provided hook every time a token is peeked at or consumed in any stream. This
+    /// is meant for tools inspecting the parse, like debuggers or visualizers.
+    pub fn set_token_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(&TokenEvent<'_>) + 'static,
+    {
+        self.token_hook = Some(Box::new(hook));
+    }
+
+    /// Run the parser, retiring the streams failing to parse instead of aborting the whole parse.
+    /// Retired streams stay paused afterwards, and the errors that retired them are returned along
+    /// with the streams that were parsed successfully. Errors recovered from in other ways (like
+    /// with [`ParseConfig::recover_array_elements`]) stay in [`State::recovered_errors`].
+    ///
+    /// ```
+    /// # use parsibes::{parse_expression, State, Streams};
+    /// let mut streams = Streams::new();
+    /// streams.add("1 + 2");
+    /// streams.add("1 +");
+    /// let mut state = State::new(streams);
+    /// let results = state.retire_failed(parse_expression).unwrap();
+    /// assert_eq!(1, results.completed.len());
+    /// assert_eq!(1, results.failed.len());
+    /// ```
+    pub fn retire_failed<F>(&mut self, parser: F) -> Result<ParseResults, Error>
+    where
+        F: FnOnce(&mut State<'src>) -> Result<(), Error>,
+    {
+        let retired = self.new_pause_id();
+        let previous_recovery = self.recovery.replace(retired);
+        let previous_errors = self.recovered_errors.len();
+        let result = parser(self);
+        self.recovery = previous_recovery;
+        result?;
+
+        // A retired stream stays paused, so the error retiring it is the last one it recorded.
+        let mut results = ParseResults::default();
+        let mut recovered = Vec::new();
+        for (id, err) in self
+            .recovered_errors
+            .split_off(previous_errors)
+            .into_iter()
+            .rev()
+        {
+            if self.streams.get(id).is_paused_by(retired) && !results.failed.contains_key(&id) {
+                results.failed.insert(id, err);
+            } else {
+                recovered.push((id, err));
+            }
+        }
+        self.recovered_errors.extend(recovered.into_iter().rev());
+        for stream in self.streams.iter_mut() {
+            if !stream.is_paused_by(retired) {
+                results.completed.push(stream.id());
+            }
+        }
+        Ok(results)
+    }
+
+    /// Run a different parser on each of the provided streams, retiring the streams failing to
+    /// parse like [`State::retire_failed`] does. Each parser only sees its own stream, while the
+    /// other streams are paused.
+    pub fn parse_per_stream<F>(
+        &mut self,
+        parsers: Vec<(StreamId, F)>,
+    ) -> Result<ParseResults, Error>
+    where
+        F: FnOnce(&mut State<'src>) -> Result<(), Error>,
+    {
+        self.retire_failed(|state| {
+            for (id, parser) in parsers {
+                state.isolate(&[id], parser)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Add a new stream while the parse is ongoing, returning its [`StreamId`]. The stream starts
+    /// unpaused at the beginning of the program, so it will be parsed along with the streams that
+    /// are currently unpaused, continuing from the current point of the grammar.
+    ///
+    /// Pauses applied before the stream was spawned don't affect it: for example, a stream spawned
+    /// while handling a `Diverge` group is only part of that group, and it's paused like the rest
+    /// of the group's streams when other groups are handled.
+    pub fn spawn_stream(&mut self, program: &'src str) -> StreamId {
+        self.streams.add(program)
+    }
+
+    /// Remove the streams that are not paused and have no tokens left, returning their IDs. This
+    /// allows an incremental parse to only keep the streams that still need parsing. The other
+    /// streams keep their IDs, while querying the removed ones with [`State::stream`] panics.
+    pub fn drain_completed(&mut self) -> Vec<StreamId> {
+        let mut drained = Vec::new();
+        self.streams.retain(|stream| {
+            let completed = !stream.is_paused() && stream.peek_token().is_none();
+            if completed {
+                drained.push(stream.id());
+            }
+            !completed
+        });
+        drained
+    }
+
+    /// Check that the next token in all unpaused streams is an identifier with the provided name,
+    /// consuming it. Strings with the same contents (like `"if"`) are not keywords.
+    pub fn expect_keyword(&mut self, keyword: &str) -> Result<(), Error> {
+        self.expect_pred(
+            |token| token.is_keyword(keyword),
+            &format!("keyword `{keyword}`"),
+        )
+    }
+
+    /// Return the stream with the highest priority among the ones whose tokens were all parsed
+    /// without errors, preferring the one added first if multiple have the same priority.
+    /// Priorities are set with [`Streams::set_priority`].
+    pub fn best_completed(&self) -> Option<StreamId> {
+        self.streams
+            .iter()
+            .filter(|stream| {
+                let handle = self.stream(stream.id());
+                !stream.is_paused() && handle.is_finished() && handle.is_success()
+            })
+            .max_by_key(|stream| (stream.priority(), Reverse(stream.id())))
+            .map(|stream| stream.id())
+    }
+
+    /// Return how many [`PauseId`]s were allocated so far. Every parsing loop and every branch
+    /// taken when the streams diverge allocates one, so this grows with the complexity of the
+    /// grammar and of the input.
+    pub fn pause_ids_allocated(&self) -> usize {
+        self.pause_ids.allocated()
+    }
+
+    /// Return the diagnostics emitted so far, removing them from the state. Diagnostics don't
+    /// stop the parse, and are meant to be shown to users (for example in an editor).
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        core::mem::take(&mut self.diagnostics)
+    }
+
+    /// Return the errors the parser recovered from, along with the stream they happened in.
+    /// Errors are only recovered if enabled in the [`ParseConfig`].
+    pub fn recovered_errors(&self) -> &[(StreamId, Error)] {
+        &self.recovered_errors
+    }
+
+    /// Return a handle to query the outcome of parsing the stream with the provided ID.
+    pub fn stream(&self, id: StreamId) -> StreamHandle<'_, 'src> {
+        StreamHandle { state: self, id }
+    }
+
+    pub fn streams(&self) -> &Streams<'src> {
+        &self.streams
+    }
+
+    /// Return which branch each stream took every time the parser diverged, in the order the
+    /// branches were taken. This is only recorded if [`ParseConfig::trace_diverge`] is enabled.
+    pub fn diverge_trace(&self) -> &[(StreamId, String)] {
+        &self.diverge_trace
+    }
+
+    /// Check that all unpaused streams agree on the next token, returning an error otherwise.
+    /// After parsing a construct shared by all streams they should be at the same token, so a
+    /// mismatch points to a bug in the grammar. To only run the check in debug builds, wrap the
+    /// call in [`debug_assert!`].
+    pub fn assert_synced(&mut self) -> Result<(), Error> {
+        if self.peek_consensus().is_some() {
+            return Ok(());
+        }
+        // Streams with no tokens left don't have a consensus, but are still synced.
+        let mut expected: Option<(StreamId, Option<Token<'src>>)> = None;
+        for stream in self.streams.iter_active_mut() {
+            let token = stream.peek_token();
+            match expected {
+                None => expected = Some((stream.id(), token)),
+                Some((id, expected)) if expected != token => bail!(
+                    "streams are not synced: {id:?} is at {expected:?}, while {:?} is at {token:?}",
+                    stream.id()
+                ),
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Cloning a state mid-parse allows exploring different continuations, by parsing each copy
+/// independently. The token hook is not cloned, and as errors can't be cloned the recovered errors
+/// are copied with their messages only.
+impl Clone for State<'_> {
+    fn clone(&self) -> Self {
+        Self {
+            streams: self.streams.clone(),
+            config: self.config.clone(),
+            diverge_trace: self.diverge_trace.clone(),
+            recovery: self.recovery,
+            recovered_errors: self
+                .recovered_errors
+                .iter()
+                .map(|(id, err)| (*id, clone_error(err)))
+                .collect(),
+            token_hook: None,
+            pause_ids: self.pause_ids.clone(),
+            diagnostics: self.diagnostics.clone(),
+        }
     }
 }
 
+/// Create a new error with the same messages as the provided one, including its context.
+fn clone_error(error: &Error) -> Error {
+    let mut messages = error.chain().map(|err| err.to_string()).collect::<Vec<_>>();
+    let root = messages
+        .pop()
+        .expect("errors always have at least one message");
+    messages
+        .into_iter()
+        .rev()
+        .fold(anyhow!("{root}"), |err, message| err.context(message))
+}
+
 impl<'src> State<'src> {
+    /// Allocate a new [`PauseId`], different from all the ones allocated before by this state.
+    pub(super) fn new_pause_id(&mut self) -> PauseId {
+        self.pause_ids.next()
+    }
+
     /// Check whether any of the streams is unpaused.
     pub(super) fn is_any_unpaused(&self) -> bool {
-        self.streams.iter().any(|s| !s.is_paused())
+        self.streams.iter_active().next().is_some()
+    }
+
+    /// Return the IDs of all the streams that are not paused.
+    pub(super) fn unpaused_ids(&self) -> Vec<StreamId> {
+        self.streams.iter_active().map(|s| s.id()).collect()
+    }
+
+    /// Execute the closure with only the provided streams unpaused (if they were unpaused to
+    /// begin with). All other streams are paused while the closure runs.
+    pub(super) fn isolate<F>(&mut self, ids: &[StreamId], f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut State<'src>) -> Result<(), Error>,
+    {
+        let pause = self.new_pause_id();
+        for stream in self.streams.iter_mut() {
+            if !ids.contains(&stream.id()) {
+                stream.pause(pause);
+            }
+        }
+        f(self)?;
+        self.unpause(pause);
+        Ok(())
     }
 
     /// Unpause all streams currently paused due to the provided [`PauseId`]. If a stream is paused
@@ -28,12 +317,89 @@ impl<'src> State<'src> {
         }
     }
 
+    /// Skip tokens in all the streams paused by the provided recovery [`PauseId`], until a token
+    /// matching `stop` is found outside of any delimiter. The stop token is not consumed, and
+    /// the streams are then unpaused.
+    pub(super) fn skip_recovered<F>(&mut self, recovery: PauseId, stop: F) -> Result<(), Error>
+    where
+        F: Fn(&Token<'src>) -> bool,
+    {
+        for stream in self.streams.iter_mut() {
+            if !stream.is_paused_by(recovery) {
+                continue;
+            }
+            let mut depth = 0;
+            loop {
+                let Some(token) = stream.peek_token() else {
+                    return Err(stream.with_notes(anyhow!("end of input")));
+                };
+                if depth == 0 && stop(&token) {
+                    break;
+                }
+                if token.matching_close().is_some() {
+                    depth += 1;
+                } else if token.is_close_delimiter() && depth > 0 {
+                    depth -= 1;
+                }
+                stream.next_token();
+            }
+            stream.maybe_unpause(recovery);
+        }
+        Ok(())
+    }
+
+    /// Return the next token if all unpaused streams agree on it, or `None` if they don't (or if
+    /// there are no tokens left).
+    pub(super) fn peek_consensus(&mut self) -> Option<Token<'src>> {
+        self.peek_nth(0)
+    }
+
+    /// Return the token `n` positions after the next one if all unpaused streams agree on it,
+    /// without consuming anything. `peek_nth(0)` is the same as [`State::peek_consensus`].
+    pub(super) fn peek_nth(&mut self, n: usize) -> Option<Token<'src>> {
+        let mut streams = self.streams.iter_active_mut();
+        let token = streams.next()?.peek_nth(n)?;
+        streams
+            .all(|stream| stream.peek_nth(n) == Some(token))
+            .then_some(token)
+    }
+
+    /// Remove the most recent note pushed with [`StreamActions::note`] from all unpaused streams.
+    pub(super) fn pop_note(&mut self) {
+        for stream in self.streams.iter_active_mut() {
+            stream.pop_note();
+        }
+    }
+
     /// Check that the next token in all unpaused streams matches the expected one.
     pub(super) fn expect(&mut self, expected: Token<'static>) -> Result<(), Error> {
+        self.expect_reporting(expected).map(|_| ())
+    }
+
+    /// Same as [`State::expect`], but return how many streams matched the expected token. Paused
+    /// streams are not counted.
+    pub(super) fn expect_reporting(&mut self, expected: Token<'static>) -> Result<usize, Error> {
+        let mut matched = 0;
         self.next_token(|next| {
-            if next.token != expected {
+            if next.token == expected {
+                matched += 1;
+            } else {
                 next.mismatch(&format!("{expected:?}"));
             }
+        })?;
+        Ok(matched)
+    }
+
+    /// Check that the next token in all unpaused streams satisfies the predicate. The description
+    /// is used in the error message if a token doesn't.
+    pub(super) fn expect_pred<P>(&mut self, pred: P, description: &str) -> Result<(), Error>
+    where
+        P: Fn(&Token<'src>) -> bool,
+    {
+        self.next_token(|next| {
+            if !pred(&next.token) {
+                next.mismatch(description);
+            }
         })
     }
 
@@ -44,7 +410,7 @@ impl<'src> State<'src> {
         F: FnMut(&mut StreamActions<'_, 'src, Token<'src>>),
     {
         self.action_on_token(action, |stream| {
-            stream.lexer.next().ok_or_else(|| anyhow!("end of input"))
+            stream.next_token().ok_or_else(|| anyhow!("end of input"))
         })
     }
 
@@ -54,10 +420,10 @@ impl<'src> State<'src> {
     where
         F: FnMut(&mut StreamActions<'_, 'src, Option<Token<'src>>>),
     {
-        self.action_on_token(action, |stream| Ok(stream.lexer.peek().cloned()))
+        self.action_on_token(action, |stream| Ok(stream.peek_token()))
     }
 
-    fn action_on_token<T: Debug, F, G>(
+    fn action_on_token<T: PeekedToken<'src>, F, G>(
         &mut self,
         mut action: F,
         token_getter: G,
@@ -66,40 +432,200 @@ impl<'src> State<'src> {
         F: FnMut(&mut StreamActions<'_, 'src, T>),
         G: Fn(&mut Stream<'src>) -> Result<T, Error>,
     {
-        for stream in self.streams.iter_mut() {
-            if stream.is_paused() {
-                continue;
-            }
-            let token = token_getter(stream)?;
-            let mut actions = StreamActions {
-                stream,
-                token,
-                error: None,
+        for stream in self.streams.iter_active_mut() {
+            let error = match token_getter(stream) {
+                Ok(token) => {
+                    let mut actions = StreamActions {
+                        stream: &mut *stream,
+                        token,
+                        consumed: T::CONSUMED,
+                        error: None,
+                        diagnostics: &mut self.diagnostics,
+                    };
+                    action(&mut actions);
+                    if let Some(hook) = &mut self.token_hook {
+                        hook(&TokenEvent {
+                            stream: actions.stream.id(),
+                            token: actions.token.peeked().map(|t| t as &dyn Debug),
+                            consumed: actions.consumed,
+                        });
+                    }
+                    actions.error
+                }
+                Err(err) => Some(err),
             };
-            action(&mut actions);
-            if let Some(err) = actions.error {
-                return Err(err);
+            if let Some(err) = error {
+                stream.mark_failed();
+                let err = stream.with_notes(err);
+                match self.recovery {
+                    Some(recovery) => {
+                        stream.pause_with_reason(recovery, "recovering from an error");
+                        self.recovered_errors.push((stream.id(), err));
+                    }
+                    None => return Err(err),
+                }
             }
         }
         Ok(())
     }
 }
 
+/// Outcome of parsing with [`State::retire_failed`].
+#[derive(Debug, Default)]
+pub struct ParseResults {
+    /// Streams that were parsed successfully.
+    pub completed: Vec<StreamId>,
+    /// Streams that failed to parse, along with why they failed.
+    pub failed: BTreeMap<StreamId, Error>,
+}
+
+/// Non-fatal message about a stream, emitted while parsing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub stream: StreamId,
+    /// How many tokens were consumed in the stream when the diagnostic was emitted.
+    pub position: usize,
+    pub message: String,
+}
+
+/// Event passed to the hook set with [`State::set_token_hook`].
+#[derive(Debug)]
+pub struct TokenEvent<'a> {
+    /// Stream the token belongs to.
+    pub stream: StreamId,
+    /// The token, or `None` if the end of the stream was peeked at.
+    pub token: Option<&'a dyn Debug>,
+    /// Whether the token was consumed, rather than only peeked at.
+    pub consumed: bool,
+}
+
+/// Tokens passed to the hook set with [`ParseConfig::primary_hook`], starting from the first
+/// token of the primary expression.
+pub struct PrimaryInput<'a, 'src> {
+    stream: &'a mut Stream<'src>,
+}
+
+impl PrimaryInput<'_, '_> {
+    /// Return the source code of the token `n` positions after the first one (so `peek(0)` is the
+    /// first token), or `None` if the input ends before it.
+    pub fn peek(&mut self, n: usize) -> Option<String> {
+        self.stream.peek_nth(n).map(|token| token.to_string())
+    }
+}
+
+/// Token types [`State::action_on_token`] can operate on.
+pub(super) trait PeekedToken<'src>: Debug {
+    /// Whether obtaining this token already consumed it from the stream.
+    const CONSUMED: bool;
+
+    fn peeked(&self) -> Option<&Token<'src>>;
+}
+
+impl<'src> PeekedToken<'src> for Token<'src> {
+    const CONSUMED: bool = true;
+
+    fn peeked(&self) -> Option<&Token<'src>> {
+        Some(self)
+    }
+}
+
+impl<'src> PeekedToken<'src> for Option<Token<'src>> {
+    const CONSUMED: bool = false;
+
+    fn peeked(&self) -> Option<&Token<'src>> {
+        self.as_ref()
+    }
+}
+
+/// Handle to query the outcome of parsing a single stream, returned by [`State::stream`].
+pub struct StreamHandle<'state, 'src> {
+    state: &'state State<'src>,
+    id: StreamId,
+}
+
+impl<'state> StreamHandle<'state, '_> {
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    /// Return how many tokens were consumed from the stream.
+    pub fn position(&self) -> usize {
+        self.state.streams.get(self.id).position()
+    }
+
+    /// Return how many tokens are left to parse in the stream, or `None` if the rest of the
+    /// stream doesn't lex. The rest of the stream is lexed the first time this is called.
+    pub fn tokens_left(&self) -> Option<usize> {
+        self.state.streams.get(self.id).tokens_left()
+    }
+
+    /// Return whether all the tokens in the stream were parsed.
+    pub fn is_finished(&self) -> bool {
+        self.tokens_left() == Some(0)
+    }
+
+    /// Return the errors the parser recovered from in this stream.
+    pub fn errors(&self) -> impl Iterator<Item = &'state Error> {
+        let id = self.id;
+        self.state
+            .recovered_errors
+            .iter()
+            .filter(move |(stream, _)| *stream == id)
+            .map(|(_, err)| err)
+    }
+
+    /// Return whether the stream was parsed to the end without errors, including the errors the
+    /// parser recovered from.
+    pub fn is_success(&self) -> bool {
+        !self.state.streams.get(self.id).has_failed() && self.is_finished()
+    }
+}
+
 pub(super) struct StreamActions<'parent, 'src, T: Debug> {
     pub(super) token: T,
     stream: &'parent mut Stream<'src>,
+    consumed: bool,
     error: Option<Error>,
+    diagnostics: &'parent mut Vec<Diagnostic>,
 }
 
 impl<T: Debug> StreamActions<'_, '_, T> {
+    /// Emit a diagnostic for this stream, without stopping the parse. Diagnostics are returned
+    /// by [`State::take_diagnostics`].
+    pub(super) fn warn(&mut self, message: &str) {
+        self.diagnostics.push(Diagnostic {
+            stream: self.stream.id(),
+            position: self.stream.position(),
+            message: message.into(),
+        });
+    }
+
     /// Cause the parsing to stop with a token mismatch error.
     pub(super) fn mismatch(&mut self, expected: &str) {
         self.error = Some(anyhow!("expected {expected}, found {:?}", self.token));
     }
 
-    /// Pause this stream with the provided [`PauseId`].
-    pub(super) fn pause(&mut self, id: PauseId) {
-        self.stream.pause(id);
+    /// Cause the parsing to stop with the provided error.
+    pub(super) fn error(&mut self, error: Error) {
+        self.error = Some(error);
+    }
+
+    /// Cause the parsing to stop with a custom error, mentioning the position of the token.
+    pub(super) fn fail(&mut self, message: &str) {
+        self.error = Some(anyhow!("{message}, at token {}", self.stream.position()));
+    }
+
+    /// Pause this stream with the provided [`PauseId`], recording why it was paused to help
+    /// debugging stuck parses.
+    pub(super) fn pause_with_reason(&mut self, id: PauseId, reason: &'static str) {
+        self.stream.pause_with_reason(id, reason);
+    }
+
+    /// Attach a note to this stream, describing what is being parsed. If parsing the stream fails
+    /// the note will be included in the error's context, until it's removed with
+    /// [`State::pop_note`].
+    pub(super) fn note(&mut self, note: &str) {
+        self.stream.push_note(note.into());
     }
 
     pub(super) fn stream_id(&self) -> StreamId {
@@ -110,6 +636,50 @@ impl<T: Debug> StreamActions<'_, '_, T> {
 impl<T: Debug> StreamActions<'_, '_, Option<T>> {
     /// Consume the peeked token.
     pub(super) fn consume(&mut self) {
-        self.stream.lexer.next();
+        self.stream.next_token();
+        self.consumed = true;
+    }
+}
+
+impl<'src> StreamActions<'_, 'src, Option<Token<'src>>> {
+    /// Consume the peeked operator, failing with an error mentioning it if the stream has no
+    /// tokens left for its operand. The `kind` of operator (like `binary`) is used in the error.
+    pub(super) fn consume_operator(&mut self, kind: Option<&str>) {
+        self.consume();
+        if self.stream.peek_token().is_none() {
+            let operator = self.token.expect("consumed an operator");
+            self.error(match kind {
+                Some(kind) => anyhow!("expected expression after {kind} operator `{operator}`"),
+                None => anyhow!("expected expression after `{operator}`"),
+            });
+        }
+    }
+
+    /// Ask the [`ParseConfig::primary_hook`] how many tokens starting from the peeked one are
+    /// part of a custom primary expression, and consume them. Return whether any was consumed.
+    pub(super) fn consume_custom_primary(&mut self, hook: &PrimaryHook) -> bool {
+        let len = (hook.0)(&mut PrimaryInput {
+            stream: &mut *self.stream,
+        });
+        if len > 0 && self.stream.peek_nth(len - 1).is_none() {
+            self.error(anyhow!(
+                "custom primary is longer than the rest of the input"
+            ));
+            return false;
+        }
+        for _ in 0..len {
+            self.consume();
+        }
+        len > 0
+    }
+
+    /// Return the kind of the peeked token, or `None` at the end of the input.
+    pub(super) fn peek_kind(&self) -> Option<TokenKind> {
+        self.token.as_ref().map(Token::kind)
+    }
+
+    /// Return whether the next token in the stream is the provided one, without consuming it.
+    pub(super) fn peek_is(&mut self, token: &Token<'_>) -> bool {
+        self.stream.peek_is(token)
     }
 }
@@ -0,0 +1,111 @@
+//! Test-only recording of the decisions taken while parsing in parallel. The final result of a
+//! parse doesn't show how the streams got there, so tests can instead snapshot the full sequence
+//! of groupings, pauses and consumed tokens, catching regressions in the parallel machinery.
+//!
+//! Recording is enabled with [`Streams::record_replay`](crate::Streams::record_replay), and the
+//! events are appended to the returned [`ReplayLog`] in the order they happen.
+
+use crate::streams::{PauseId, StreamId};
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt::{Debug, Formatter};
+
+pub(crate) type ReplayLog = Rc<RefCell<Vec<ReplayEvent>>>;
+
+/// Events are shown one per line in snapshots, prefixed by the stream they apply to.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) enum ReplayEvent {
+    /// The streams were grouped together when the parser diverged.
+    Diverge {
+        group: String,
+        streams: Vec<StreamId>,
+    },
+    Pause {
+        stream: StreamId,
+        pause: PauseId,
+        reason: Option<&'static str>,
+    },
+    /// One of the pauses with the ID was removed from the stream, which might still be paused by
+    /// other pauses.
+    Unpause {
+        stream: StreamId,
+        pause: PauseId,
+    },
+    Consume {
+        stream: StreamId,
+        token: String,
+    },
+}
+
+impl Debug for ReplayEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ReplayEvent::Diverge { group, streams } => {
+                write!(f, "{streams:?} diverge to {group}")
+            }
+            ReplayEvent::Pause {
+                stream,
+                pause,
+                reason,
+            } => {
+                write!(f, "{stream:?} pause {pause:?}")?;
+                if let Some(reason) = reason {
+                    write!(f, " ({reason})")?;
+                }
+                Ok(())
+            }
+            ReplayEvent::Unpause { stream, pause } => write!(f, "{stream:?} unpause {pause:?}"),
+            ReplayEvent::Consume { stream, token } => write!(f, "{stream:?} consume {token}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse_expression, State, Streams};
+    use insta::assert_debug_snapshot;
+
+    #[test]
+    fn test_replay_expression() {
+        let mut streams = Streams::new();
+        streams.add("-1");
+        streams.add("(x)");
+        let log = streams.record_replay();
+        parse_expression(&mut State::new(streams)).unwrap();
+
+        assert_debug_snapshot!(log.borrow(), @r###"
+        [
+            StreamId(0) consume Token( - ),
+            [StreamId(0)] diverge to _,
+            [StreamId(1)] diverge to Token::OpenParen,
+            StreamId(0) pause PauseId(1),
+            StreamId(1) consume Token( ( ),
+            [StreamId(1)] diverge to _,
+            StreamId(0) pause PauseId(4),
+            StreamId(1) consume Token( x ),
+            StreamId(0) unpause PauseId(4),
+            StreamId(1) pause PauseId(5) (end of postfix),
+            StreamId(1) unpause PauseId(5),
+            StreamId(1) pause PauseId(3) (end of expression),
+            StreamId(1) unpause PauseId(3),
+            StreamId(1) consume Token( ) ),
+            StreamId(1) pause PauseId(2) (end of parentheses),
+            StreamId(1) unpause PauseId(2),
+            StreamId(0) unpause PauseId(1),
+            StreamId(1) pause PauseId(7),
+            StreamId(0) consume Token( 1 ),
+            StreamId(1) unpause PauseId(7),
+            StreamId(0) pause PauseId(8) (end of postfix),
+            StreamId(1) pause PauseId(8) (end of postfix),
+            StreamId(0) unpause PauseId(8),
+            StreamId(1) unpause PauseId(8),
+            StreamId(0) pause PauseId(0) (end of expression),
+            StreamId(1) pause PauseId(0) (end of expression),
+            StreamId(0) unpause PauseId(0),
+            StreamId(1) unpause PauseId(0),
+        ]
+        "###);
+    }
+}
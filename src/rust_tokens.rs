@@ -0,0 +1,346 @@
+//! Conversion between [`Token`]s and the tokens of [`proc_macro2`], to parse real Rust tokens.
+//!
+//! Only the subset of tokens both sides can represent is converted, and some conversions are
+//! lossy:
+//!
+//! * [`Token::Newline`] and [`Token::Unknown`] have no equivalent, and return an error.
+//! * The contents of [`Token::String`] are not unescaped by the lexer, so they are escaped again
+//!   when converted (a `\n` in the source becomes `\\n`).
+//! * [`Token::NumberText`] keeps its text, while [`Token::Number`] is converted without leading
+//!   zeros. Negative numbers are converted to a literal starting with `-`, which is converted back
+//!   as a [`Token::Dash`] followed by the number.
+//! * Rust tokens this crate has no equivalent for return an error when converted back: lifetimes,
+//!   raw identifiers, floats, suffixed or non-decimal numbers, raw strings, and strings containing
+//!   escaped quotes.
+//! * Groups without delimiters are flattened, and identifiers never become [`Token::Keyword`].
+
+use crate::lexer::{unescape_byte_string, unescape_char, Lexer, Token};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use anyhow::{anyhow, bail, ensure, Error};
+use core::ops::Range;
+use proc_macro2::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+/// Convert the tokens into a [`TokenStream`]. As delimiters are represented as groups in
+/// [`proc_macro2`], they must be balanced.
+pub(crate) fn to_token_stream(tokens: &[Token<'_>]) -> Result<TokenStream, Error> {
+    // Each entry is a group being built: its delimiter and the tokens collected so far.
+    let mut stack: Vec<(Option<Delimiter>, Vec<TokenTree>)> = Vec::new();
+    stack.push((None, Vec::new()));
+
+    for token in tokens {
+        let (current, trees) = stack.last_mut().expect("the top level is never removed");
+        match token {
+            Token::OpenParen => stack.push((Some(Delimiter::Parenthesis), Vec::new())),
+            Token::OpenSquare => stack.push((Some(Delimiter::Bracket), Vec::new())),
+            Token::OpenBrace => stack.push((Some(Delimiter::Brace), Vec::new())),
+            Token::CloseParen | Token::CloseSquare | Token::CloseBrace => {
+                let Some(delimiter) = *current else {
+                    bail!("unbalanced delimiter {token}");
+                };
+                let expected = match delimiter {
+                    Delimiter::Parenthesis => Token::CloseParen,
+                    Delimiter::Bracket => Token::CloseSquare,
+                    _ => Token::CloseBrace,
+                };
+                if *token != expected {
+                    bail!("expected {expected}, found {token}");
+                }
+                let group = Group::new(delimiter, trees.drain(..).collect());
+                stack.pop();
+                let (_, parent) = stack.last_mut().expect("the top level is never removed");
+                parent.push(group.into());
+            }
+            Token::StarStar => trees.extend(punct("**")),
+            Token::DotDot => trees.extend(punct("..")),
+            Token::Number(number) => trees.push(Literal::i64_unsuffixed(*number).into()),
+            Token::NumberText { text, .. } => trees.push(
+                text.parse::<Literal>()
+                    .map_err(|err| anyhow!("invalid number {text}: {err}"))?
+                    .into(),
+            ),
+            Token::String(string) => trees.push(Literal::string(string).into()),
+            Token::ByteString(_) => {
+                let bytes = token.byte_string_value().expect("token is a byte string");
+                trees.push(Literal::byte_string(&bytes).into());
+            }
+            Token::Char(c) => trees.push(Literal::character(*c).into()),
+            Token::Ident(ident) | Token::Keyword(ident) => {
+                trees.push(Ident::new(ident, Span::call_site()).into())
+            }
+            Token::Newline | Token::Unknown(_) => {
+                bail!("{token:?} can't be converted to a Rust token")
+            }
+            other => trees.extend(punct(&other.to_string())),
+        }
+    }
+
+    match stack.pop() {
+        Some((None, trees)) if stack.is_empty() => Ok(trees.into_iter().collect()),
+        _ => bail!("unclosed delimiter"),
+    }
+}
+
+/// Convert the [`TokenStream`] into tokens, returning an error if it contains tokens with no
+/// equivalent in this crate.
+pub(crate) fn from_token_stream(stream: TokenStream) -> Result<RustTokens, Error> {
+    let mut tokens = RustTokens {
+        text: String::new(),
+        converted: Vec::new(),
+    };
+    tokens.push_stream(stream)?;
+    Ok(tokens)
+}
+
+/// Return the source of the [`TokenStream`], to add it to [`Streams`](crate::Streams) (which lex
+/// their programs from source). Unlike [`from_token_stream`], tokens with no equivalent in this
+/// crate are not rejected, and are lexed as whatever their source looks like.
+pub(crate) fn source_of(stream: &TokenStream) -> String {
+    stream.to_string()
+}
+
+/// Tokens converted from a [`TokenStream`]. As [`Token`] borrows its contents, the text of the
+/// identifiers, strings and punctuation is stored here, and [`RustTokens::tokens`] returns tokens
+/// borrowing from it.
+pub(crate) struct RustTokens {
+    text: String,
+    converted: Vec<Converted>,
+}
+
+enum Converted {
+    Token(Token<'static>),
+    Ident(Range<usize>),
+    String(Range<usize>),
+    ByteString(Range<usize>),
+    /// Joint punctuation (like `**`), lexed to merge it the same way the lexer would.
+    Punct(Range<usize>),
+}
+
+impl RustTokens {
+    pub(crate) fn tokens(&self) -> Vec<Token<'_>> {
+        let mut tokens = Vec::new();
+        for converted in &self.converted {
+            match converted {
+                Converted::Token(token) => tokens.push(*token),
+                Converted::Ident(range) => tokens.push(Token::Ident(&self.text[range.clone()])),
+                Converted::String(range) => tokens.push(Token::String(&self.text[range.clone()])),
+                Converted::ByteString(range) => {
+                    tokens.push(Token::ByteString(self.text[range.clone()].as_bytes()))
+                }
+                Converted::Punct(range) => tokens.extend(Lexer::new(&self.text[range.clone()])),
+            }
+        }
+        tokens
+    }
+
+    fn push_stream(&mut self, stream: TokenStream) -> Result<(), Error> {
+        let mut trees = stream.into_iter().peekable();
+        while let Some(tree) = trees.next() {
+            match tree {
+                TokenTree::Group(group) => {
+                    let (open, close) = match group.delimiter() {
+                        Delimiter::Parenthesis => (Token::OpenParen, Token::CloseParen),
+                        Delimiter::Bracket => (Token::OpenSquare, Token::CloseSquare),
+                        Delimiter::Brace => (Token::OpenBrace, Token::CloseBrace),
+                        Delimiter::None => {
+                            self.push_stream(group.stream())?;
+                            continue;
+                        }
+                    };
+                    self.converted.push(Converted::Token(open));
+                    self.push_stream(group.stream())?;
+                    self.converted.push(Converted::Token(close));
+                }
+                TokenTree::Ident(ident) => {
+                    let ident = ident.to_string();
+                    ensure!(
+                        !ident.starts_with("r#"),
+                        "raw identifier {ident} is not supported"
+                    );
+                    let range = self.push_text(&ident);
+                    self.converted.push(Converted::Ident(range));
+                }
+                TokenTree::Punct(mut punct) => {
+                    let start = self.text.len();
+                    loop {
+                        ensure!(punct.as_char() != '\'', "lifetimes are not supported");
+                        self.text.push(punct.as_char());
+                        if punct.spacing() != Spacing::Joint {
+                            break;
+                        }
+                        match trees.next_if(|next| matches!(next, TokenTree::Punct(_))) {
+                            Some(TokenTree::Punct(next)) => punct = next,
+                            _ => break,
+                        }
+                    }
+                    self.converted
+                        .push(Converted::Punct(start..self.text.len()));
+                }
+                TokenTree::Literal(literal) => {
+                    let literal = literal.to_string();
+                    // Only the compiler keeps the sign inside the literal, proc-macro2 splits it.
+                    let literal = match literal.strip_prefix('-') {
+                        Some(rest) => {
+                            self.converted.push(Converted::Token(Token::Dash));
+                            rest
+                        }
+                        None => &literal,
+                    };
+                    let converted = self.convert_literal(literal)?;
+                    self.converted.push(converted);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn convert_literal(&mut self, literal: &str) -> Result<Converted, Error> {
+        let quoted = |prefix: &str, quote: char| {
+            literal
+                .strip_prefix(prefix)
+                .and_then(|rest| rest.strip_suffix(quote))
+        };
+        if let Ok(number) = literal.parse::<i64>() {
+            Ok(Converted::Token(Token::Number(number)))
+        } else if let Some(contents) = quoted("b\"", '"') {
+            unescape_byte_string(contents.as_bytes())?;
+            Ok(Converted::ByteString(self.push_text(contents)))
+        } else if let Some(contents) = quoted("\"", '"') {
+            // The lexer ends strings at the first quote, even if it's escaped.
+            ensure!(
+                !contents.contains('"'),
+                "string {literal} containing quotes is not supported"
+            );
+            Ok(Converted::String(self.push_text(contents)))
+        } else if let Some(contents) = quoted("'", '\'') {
+            let mut chars = contents.chars();
+            let c = match (chars.next(), chars.next(), chars.next()) {
+                (Some('\\'), Some(escaped), None) => unescape_char(escaped),
+                (Some(c), None, None) => Some(c),
+                _ => None,
+            };
+            let c = c.ok_or_else(|| anyhow!("char {literal} is not supported"))?;
+            Ok(Converted::Token(Token::Char(c)))
+        } else {
+            bail!("literal {literal} is not supported")
+        }
+    }
+
+    fn push_text(&mut self, text: &str) -> Range<usize> {
+        let start = self.text.len();
+        self.text.push_str(text);
+        start..self.text.len()
+    }
+}
+
+/// Convert a multi-character punctuation into joint [`Punct`]s.
+fn punct(text: &str) -> Vec<TokenTree> {
+    let count = text.chars().count();
+    text.chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let spacing = if i + 1 < count {
+                Spacing::Joint
+            } else {
+                Spacing::Alone
+            };
+            Punct::new(c, spacing).into()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    #[test]
+    fn test_round_trip() {
+        let input = "foo ( [ 1 , 2 ] ) ** b\"ab\" { 'c' .. \"s\" } $ x ; - 3 '\\n'";
+        let tokens = Lexer::tokenize_all(input).unwrap();
+        let stream = to_token_stream(&tokens).unwrap();
+        assert_eq!(tokens, from_token_stream(stream.clone()).unwrap().tokens());
+
+        let source = source_of(&stream);
+        assert_eq!(tokens, Lexer::tokenize_all(&source).unwrap());
+    }
+
+    #[test]
+    fn test_from_token_stream_punct() {
+        let stream: TokenStream = [
+            Punct::new('*', Spacing::Joint).into(),
+            Punct::new('*', Spacing::Alone).into(),
+            Punct::new('*', Spacing::Alone).into(),
+            Punct::new('.', Spacing::Joint).into(),
+            Punct::new('.', Spacing::Joint).into(),
+            Punct::new('=', Spacing::Alone).into(),
+            TokenTree::from(Group::new(
+                Delimiter::None,
+                punct("-").into_iter().collect(),
+            )),
+            Literal::i64_unsuffixed(-3).into(),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            vec![
+                Token::StarStar,
+                Token::Star,
+                Token::DotDot,
+                Token::Equals,
+                Token::Dash,
+                Token::Dash,
+                Token::Number(3),
+            ],
+            from_token_stream(stream).unwrap().tokens()
+        );
+    }
+
+    #[test]
+    fn test_negative_number() {
+        let stream = to_token_stream(&[Token::Number(-3)]).unwrap();
+        assert_eq!(
+            vec![Token::Dash, Token::Number(3)],
+            from_token_stream(stream).unwrap().tokens()
+        );
+    }
+
+    #[test]
+    fn test_from_token_stream_unsupported() {
+        let convert = |tree: TokenTree| {
+            let stream = TokenStream::from_iter([tree]);
+            from_token_stream(stream).err().unwrap().to_string()
+        };
+        let literal = |text: &str| text.parse::<Literal>().unwrap().into();
+
+        assert_eq!("literal 1.5 is not supported", convert(literal("1.5")));
+        assert_eq!("literal 1u8 is not supported", convert(literal("1u8")));
+        assert_eq!(
+            "string \"a\\\"b\" containing quotes is not supported",
+            convert(literal("\"a\\\"b\""))
+        );
+        assert_eq!(
+            "char '\\u{1}' is not supported",
+            convert(literal("'\\u{1}'"))
+        );
+        assert_eq!(
+            "lifetimes are not supported",
+            convert(Punct::new('\'', Spacing::Joint).into())
+        );
+        assert_eq!(
+            "raw identifier r#fn is not supported",
+            convert(Ident::new_raw("fn", Span::call_site()).into())
+        );
+    }
+
+    #[test]
+    fn test_unbalanced() {
+        let tokens = Lexer::tokenize_all("( ]").unwrap();
+        let err = to_token_stream(&tokens).unwrap_err();
+        assert_eq!("expected ), found ]", err.to_string());
+
+        let tokens = Lexer::tokenize_all("[ 1").unwrap();
+        let err = to_token_stream(&tokens).unwrap_err();
+        assert_eq!("unclosed delimiter", err.to_string());
+    }
+}
@@ -1,11 +1,21 @@
-use crate::lexer::Lexer;
-use std::collections::HashSet;
-use std::iter::Peekable;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::lexer::{Lexer, Token};
+#[cfg(test)]
+use crate::replay::{ReplayEvent, ReplayLog};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use anyhow::{ensure, Error};
+use core::cell::OnceCell;
 
-#[derive(Default)]
+/// Cloning the streams copies their progress and pauses, so that each copy can be parsed
+/// independently (for example to try different continuations).
+#[derive(Default, Clone)]
 pub struct Streams<'src> {
+    /// Sorted by ID, as IDs are allocated in increasing order and never reused.
     streams: Vec<Stream<'src>>,
+    next_id: usize,
+    #[cfg(test)]
+    replay: Option<ReplayLog>,
 }
 
 impl<'src> Streams<'src> {
@@ -13,13 +23,89 @@ impl<'src> Streams<'src> {
         Streams::default()
     }
 
-    pub fn add(&mut self, program: &'src str) {
-        let id = StreamId(self.streams.len());
+    /// Add a new program to parse, returning the [`StreamId`] identifying it.
+    pub fn add(&mut self, program: &'src str) -> StreamId {
+        self.push(program, Lexer::new(program), VecDeque::new())
+    }
+
+    /// Same as [`Streams::add`], but return an error if the program contains more than
+    /// `max_tokens` tokens, or if it contains a malformed literal.
+    pub fn add_with_limit(
+        &mut self,
+        program: &'src str,
+        max_tokens: usize,
+    ) -> Result<StreamId, Error> {
+        // The lexed tokens are kept as lookahead, to avoid lexing the program again when parsing.
+        let mut lexer = Lexer::new(program);
+        let mut lookahead = VecDeque::new();
+        while let Some(token) = lexer.try_next()? {
+            ensure!(
+                lookahead.len() < max_tokens,
+                "program contains more than {max_tokens} tokens"
+            );
+            lookahead.push_back(token);
+        }
+        Ok(self.push(program, lexer, lookahead))
+    }
+
+    fn push(
+        &mut self,
+        program: &'src str,
+        lexer: Lexer<'src>,
+        lookahead: VecDeque<Token<'src>>,
+    ) -> StreamId {
+        let id = StreamId(self.next_id);
+        self.next_id += 1;
         self.streams.push(Stream {
-            lexer: Lexer::new(program).peekable(),
-            pause: HashSet::new(),
+            lexer,
+            lookahead,
+            source: program,
+            position: 0,
+            len: OnceCell::new(),
+            failed: false,
+            pause: BTreeMap::new(),
+            notes: Vec::new(),
+            priority: 0,
             id,
+            #[cfg(test)]
+            replay: self.replay.clone(),
         });
+        id
+    }
+
+    /// Start recording the decisions taken while parsing the streams (including the ones added
+    /// later) in the returned log. See the [`replay`](crate::replay) module for more details.
+    #[cfg(test)]
+    pub(crate) fn record_replay(&mut self) -> ReplayLog {
+        let log = ReplayLog::default();
+        self.replay = Some(log.clone());
+        for stream in &mut self.streams {
+            stream.replay = Some(log.clone());
+        }
+        log
+    }
+
+    #[cfg(test)]
+    pub(crate) fn record(&self, event: ReplayEvent) {
+        if let Some(log) = &self.replay {
+            log.borrow_mut().push(event);
+        }
+    }
+
+    /// Set the priority of a stream, used to pick between multiple streams parsed successfully.
+    /// Higher values take precedence, and all streams start with a priority of zero.
+    pub fn set_priority(&mut self, id: StreamId, priority: i32) {
+        let index = self.index_of(id);
+        self.streams[index].priority = priority;
+    }
+
+    /// Return how many streams there are.
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
     }
 
     pub(crate) fn iter(&self) -> impl Iterator<Item = &Stream<'src>> {
@@ -29,52 +115,426 @@ impl<'src> Streams<'src> {
     pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut Stream<'src>> {
         self.streams.iter_mut()
     }
+
+    /// Capture the current state of all streams. This is meant to be used in tests, to assert the
+    /// progress of each stream while parsing in parallel.
+    pub fn snapshot(&self) -> StreamsSnapshot<'src> {
+        // Pause IDs keep increasing during the parse, so they would change every time the grammar
+        // changes. Renumber them to only depend on the pauses currently active.
+        let mut pause_ids = self
+            .streams
+            .iter()
+            .flat_map(|s| s.pause.keys().copied())
+            .collect::<Vec<_>>();
+        pause_ids.sort_by_key(|id| id.0);
+        pause_ids.dedup();
+
+        let streams = self
+            .streams
+            .iter()
+            .map(|stream| {
+                let mut pauses = stream
+                    .pause
+                    .iter()
+                    .map(|(id, pause)| {
+                        let position = pause_ids.iter().position(|p| p == id).unwrap();
+                        (position, pause.reason)
+                    })
+                    .collect::<Vec<_>>();
+                pauses.sort();
+                StreamSnapshot {
+                    source: stream.source,
+                    position: stream.position,
+                    paused_by: pauses.iter().map(|(id, _)| *id).collect(),
+                    pause_reasons: pauses.iter().map(|(_, reason)| *reason).collect(),
+                }
+            })
+            .collect();
+
+        StreamsSnapshot { streams }
+    }
+
+    pub(crate) fn get(&self, id: StreamId) -> &Stream<'src> {
+        &self.streams[self.index_of(id)]
+    }
+
+    /// Remove the streams the predicate returns `false` for. The IDs of the other streams don't
+    /// change, and the IDs of the removed streams are not reused.
+    pub(crate) fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut Stream<'src>) -> bool,
+    {
+        self.streams.retain_mut(f);
+    }
+
+    fn index_of(&self, id: StreamId) -> usize {
+        self.streams
+            .binary_search_by_key(&id, |stream| stream.id)
+            .unwrap_or_else(|_| panic!("{id:?} was removed"))
+    }
+
+    /// Iterate over all the streams that are not paused.
+    pub(crate) fn iter_active(&self) -> impl Iterator<Item = &Stream<'src>> {
+        self.streams.iter().filter(|s| !s.is_paused())
+    }
+
+    /// Iterate mutably over all the streams that are not paused.
+    pub(crate) fn iter_active_mut(&mut self) -> impl Iterator<Item = &mut Stream<'src>> {
+        self.streams.iter_mut().filter(|s| !s.is_paused())
+    }
 }
 
+#[derive(Clone)]
 pub(crate) struct Stream<'src> {
-    pub(crate) lexer: Peekable<Lexer<'src>>,
+    lexer: Lexer<'src>,
+    /// Tokens already lexed to look ahead, but not consumed yet.
+    lookahead: VecDeque<Token<'src>>,
+    source: &'src str,
+    position: usize,
+    /// Total number of tokens in the stream, or `None` if the source doesn't lex. This is only
+    /// computed when needed, as it requires lexing the whole source.
+    len: OnceCell<Option<usize>>,
+    /// Whether an error was returned while parsing this stream.
+    failed: bool,
     id: StreamId,
-    pause: HashSet<PauseId>,
+    pause: BTreeMap<PauseId, Pause>,
+    notes: Vec<String>,
+    priority: i32,
+    #[cfg(test)]
+    replay: Option<ReplayLog>,
 }
 
-impl Stream<'_> {
+impl<'src> Stream<'src> {
     pub(crate) fn id(&self) -> StreamId {
         self.id
     }
 
+    pub(crate) fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    /// Return how many tokens were consumed so far.
+    pub(crate) fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Consume the next token in the stream.
+    pub(crate) fn next_token(&mut self) -> Option<Token<'src>> {
+        let token = self.lookahead.pop_front().or_else(|| self.lexer.next());
+        if token.is_some() {
+            self.position += 1;
+        }
+        #[cfg(test)]
+        if let Some(token) = &token {
+            self.record(ReplayEvent::Consume {
+                stream: self.id,
+                token: alloc::format!("{token:?}"),
+            });
+        }
+        token
+    }
+
+    /// Return how many tokens are left in the stream without consuming them, or `None` if the
+    /// rest of the source doesn't lex. The source is only lexed the first time this is called.
+    pub(crate) fn tokens_left(&self) -> Option<usize> {
+        let len = self.len.get_or_init(|| {
+            let mut lexer = self.lexer.clone();
+            let mut len = self.position + self.lookahead.len();
+            while lexer.try_next().ok()?.is_some() {
+                len += 1;
+            }
+            Some(len)
+        });
+        Some((*len)? - self.position)
+    }
+
+    /// Record that an error was returned while parsing this stream.
+    pub(crate) fn mark_failed(&mut self) {
+        self.failed = true;
+    }
+
+    /// Return whether an error was returned while parsing this stream, even if the parser
+    /// recovered from it.
+    pub(crate) fn has_failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Return the next token in the stream without consuming it.
+    pub(crate) fn peek_token(&mut self) -> Option<Token<'src>> {
+        self.peek_nth(0)
+    }
+
+    /// Return whether the next token in the stream is the provided one, without consuming it.
+    pub(crate) fn peek_is(&mut self, token: &Token<'_>) -> bool {
+        self.peek_token().as_ref() == Some(token)
+    }
+
+    /// Return the token `n` positions after the next one (so `peek_nth(0)` is the next token),
+    /// without consuming anything.
+    pub(crate) fn peek_nth(&mut self, n: usize) -> Option<Token<'src>> {
+        while self.lookahead.len() <= n {
+            self.lookahead.push_back(self.lexer.next()?);
+        }
+        self.lookahead.get(n).copied()
+    }
+
     /// Mark the stream to be paused, with the provided pause ID. The only effect of this is that
     /// [`Stream::maybe_unpause`] will return `false`: it's up to the user to verify whether the
     /// stream is paused before pulling tokens from it.
     ///
     /// It's possible to call this multiple times with different [`PauseId`], which will mark the
-    /// stream to be paused by all of them.
+    /// stream to be paused by all of them. Pausing multiple times with the same [`PauseId`] is also
+    /// allowed, and requires the same number of [`Stream::maybe_unpause`] calls to undo.
     pub(crate) fn pause(&mut self, id: PauseId) {
-        self.pause.insert(id);
+        self.pause.entry(id).or_default().count += 1;
+        #[cfg(test)]
+        self.record(ReplayEvent::Pause {
+            stream: self.id,
+            pause: id,
+            reason: None,
+        });
+    }
+
+    /// Same as [`Stream::pause`], but also record why the stream was paused. The reason is only
+    /// used for debugging, and is shown in [`Streams::snapshot`].
+    pub(crate) fn pause_with_reason(&mut self, id: PauseId, reason: &'static str) {
+        let pause = self.pause.entry(id).or_default();
+        pause.count += 1;
+        pause.reason = Some(reason);
+        #[cfg(test)]
+        self.record(ReplayEvent::Pause {
+            stream: self.id,
+            pause: id,
+            reason: Some(reason),
+        });
     }
 
     /// If the stream is paused by the provided [`PauseId`] unpause it, otherwise do nothing.
     ///
     /// Note that it's possible to pause a stream with multiple [`PauseId`]. In that case, the
-    /// stream will only be unpaused if *all* of the pauses are removed.
+    /// stream will only be unpaused if *all* of the pauses are removed. If the stream was paused
+    /// multiple times with the same [`PauseId`], this only removes one of them.
     pub(crate) fn maybe_unpause(&mut self, id: PauseId) {
-        self.pause.remove(&id);
+        if let Some(pause) = self.pause.get_mut(&id) {
+            pause.count -= 1;
+            if pause.count == 0 {
+                self.pause.remove(&id);
+            }
+            #[cfg(test)]
+            self.record(ReplayEvent::Unpause {
+                stream: self.id,
+                pause: id,
+            });
+        }
     }
 
     /// Return whether the stream is supposed to be paused.
     pub(crate) fn is_paused(&self) -> bool {
         !self.pause.is_empty()
     }
+
+    /// Return whether the stream is paused by the provided [`PauseId`], regardless of any other
+    /// pause applied to it.
+    pub(crate) fn is_paused_by(&self, id: PauseId) -> bool {
+        self.pause.contains_key(&id)
+    }
+
+    /// Push a note describing what is currently being parsed in this stream. Notes are attached
+    /// as context to errors returned by [`Stream::with_notes`].
+    pub(crate) fn push_note(&mut self, note: String) {
+        self.notes.push(note);
+    }
+
+    /// Remove the most recently pushed note, if any.
+    pub(crate) fn pop_note(&mut self) {
+        self.notes.pop();
+    }
+
+    #[cfg(test)]
+    fn record(&self, event: ReplayEvent) {
+        if let Some(log) = &self.replay {
+            log.borrow_mut().push(event);
+        }
+    }
+
+    /// Attach all the notes currently pushed onto the stream as context of the error, with the
+    /// most recently pushed note being the innermost one.
+    pub(crate) fn with_notes(&self, mut error: Error) -> Error {
+        for note in self.notes.iter().rev() {
+            error = error.context(note.clone());
+        }
+        error
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId(usize);
+
+#[derive(Default, Clone)]
+struct Pause {
+    /// How many times the stream was paused with the same [`PauseId`].
+    count: usize,
+    reason: Option<&'static str>,
+}
+
+/// State of all the streams at a point in time, returned by [`Streams::snapshot`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamsSnapshot<'src> {
+    pub streams: Vec<StreamSnapshot<'src>>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub(crate) struct StreamId(usize);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamSnapshot<'src> {
+    /// The program being parsed by the stream.
+    pub source: &'src str,
+    /// How many tokens were consumed so far.
+    pub position: usize,
+    /// Which pauses are currently applied to the stream. Pauses are numbered in the order they
+    /// were created, starting from zero for the oldest pause present in the snapshot.
+    pub paused_by: Vec<usize>,
+    /// Reason provided for each of the pauses in `paused_by` (at the same index), if any.
+    pub pause_reasons: Vec<Option<&'static str>>,
+}
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub(crate) struct PauseId(usize);
 
-impl PauseId {
-    pub(crate) fn new() -> PauseId {
-        static COUNTER: AtomicUsize = AtomicUsize::new(0);
-        PauseId(COUNTER.fetch_add(1, Ordering::Relaxed))
+/// Allocator of [`PauseId`]s, each different from all the ones allocated before.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PauseIds {
+    allocated: usize,
+}
+
+impl PauseIds {
+    pub(crate) fn next(&mut self) -> PauseId {
+        let id = PauseId(self.allocated);
+        self.allocated += 1;
+        id
+    }
+
+    /// Return how many IDs were allocated so far.
+    pub(crate) fn allocated(&self) -> usize {
+        self.allocated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_len() {
+        let mut streams = Streams::new();
+        assert!(streams.is_empty());
+
+        streams.add("1");
+        streams.add("2");
+        assert_eq!(2, streams.len());
+        assert!(!streams.is_empty());
+    }
+
+    #[test]
+    fn test_peek_nth() {
+        let mut streams = Streams::new();
+        streams.add("1 + 2");
+        let stream = streams.iter_mut().next().unwrap();
+
+        assert_eq!(Some(Token::Number(2)), stream.peek_nth(2));
+        assert_eq!(Some(3), stream.tokens_left());
+        assert_eq!(Some(Token::Number(1)), stream.next_token());
+        assert_eq!(Some(Token::Plus), stream.peek_token());
+        assert_eq!(1, stream.position());
+        assert_eq!(Some(2), stream.tokens_left());
+        assert!(stream.peek_is(&Token::Plus));
+        assert!(!stream.peek_is(&Token::Dash));
+
+        let mut streams = Streams::new();
+        streams.add("]");
+        let stream = streams.iter_mut().next().unwrap();
+        assert!(stream.peek_is(&Token::CloseSquare));
+        stream.next_token();
+        assert!(!stream.peek_is(&Token::CloseSquare));
+    }
+
+    #[test]
+    fn test_add_with_limit() {
+        let mut streams = Streams::new();
+        assert!(streams.add_with_limit("[1, 2]", 5).is_ok());
+        let err = streams.add_with_limit("[1, 2, 3]", 5).unwrap_err();
+        assert_eq!("program contains more than 5 tokens", err.to_string());
+        let err = streams.add_with_limit("\"unterminated", 5).unwrap_err();
+        assert_eq!("unterminated string", err.to_string());
+        assert_eq!(1, streams.len());
+
+        let stream = streams.iter_mut().next().unwrap();
+        assert_eq!(Some(5), stream.tokens_left());
+        assert_eq!(Some(Token::OpenSquare), stream.next_token());
+        assert_eq!(Some(4), stream.tokens_left());
+    }
+
+    #[test]
+    fn test_iter_active() {
+        let mut streams = Streams::new();
+        let first = streams.add("1");
+        let second = streams.add("2");
+
+        let pause = PauseIds::default().next();
+        for stream in streams.iter_active_mut() {
+            if stream.id() == first {
+                stream.pause(pause);
+            }
+        }
+
+        let active = streams
+            .iter_active_mut()
+            .map(|s| s.id())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![second], active);
+        let active = streams.iter_active().map(|s| s.id()).collect::<Vec<_>>();
+        assert_eq!(vec![second], active);
+    }
+
+    #[test]
+    fn test_nested_pause() {
+        let mut streams = Streams::new();
+        streams.add("1");
+        let stream = streams.iter_mut().next().unwrap();
+
+        let mut pause_ids = PauseIds::default();
+        let outer = pause_ids.next();
+        let inner = pause_ids.next();
+        stream.pause(outer);
+        stream.pause(inner);
+        stream.pause(outer);
+
+        stream.maybe_unpause(outer);
+        assert!(stream.is_paused_by(outer));
+        stream.maybe_unpause(inner);
+        assert!(!stream.is_paused_by(inner));
+        assert!(stream.is_paused());
+        stream.maybe_unpause(outer);
+        assert!(!stream.is_paused());
+
+        // Unpausing more times than the stream was paused is a no-op.
+        stream.maybe_unpause(outer);
+        assert!(!stream.is_paused());
+    }
+
+    #[test]
+    fn test_pause_reason() {
+        let mut streams = Streams::new();
+        streams.add("1");
+
+        let stream = streams.iter_active_mut().next().unwrap();
+        let mut pause_ids = PauseIds::default();
+        stream.pause(pause_ids.next());
+        stream.pause_with_reason(pause_ids.next(), "end of array");
+
+        let snapshot = streams.snapshot();
+        assert_eq!(vec![0, 1], snapshot.streams[0].paused_by);
+        assert_eq!(
+            vec![None, Some("end of array")],
+            snapshot.streams[0].pause_reasons
+        );
     }
 }
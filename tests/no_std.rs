@@ -0,0 +1,19 @@
+//! Ensure the crate keeps building without the `std` feature.
+
+use std::process::Command;
+
+#[test]
+fn test_build_no_std() {
+    let manifest_dir = env!("CARGO_MANIFEST_DIR");
+    let output = Command::new(env!("CARGO"))
+        .args(["check", "--lib", "--no-default-features", "--target-dir"])
+        .arg(format!("{manifest_dir}/target/no-std"))
+        .current_dir(manifest_dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}